@@ -0,0 +1,97 @@
+//! Measures the latency of storing messages of increasing size in Redis.
+//!
+//! The Redis server to use is read from `BRIZZO_BENCH_REDIS`; if it is not
+//! set, the benchmark is skipped.
+
+use std::env;
+use std::sync::atomic;
+use std::time;
+
+use criterion::{
+    criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion,
+    Throughput,
+};
+
+use maze::initialize;
+
+use brizzo::messages::xid;
+use brizzo::messages::Message;
+use brizzo::store::{RedisStore, Store};
+
+/// The environment variable containing the Redis connection string.
+const CONNECTION_STRING: &str = "BRIZZO_BENCH_REDIS";
+
+/// The texts to store; their lengths determine the number of rooms.
+const TEXTS: &[&str] = &[
+    "A",
+    "ABCD",
+    "ABCDEFGHIJKLMNOP",
+    "ABCDEFGHIJKLMNOPQRSTUVWXYZABCDEFGHIJKLMNOPQRSTUVWXYZABCDEFGHIJKL",
+];
+
+/// The pipeline sizes to compare.
+const BATCH_SIZES: &[usize] = &[1, 64, 256, 1024];
+
+/// A counter used to generate unique message names.
+static COUNTER: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+
+fn put_message(c: &mut Criterion) {
+    let connection_string = match env::var(CONNECTION_STRING) {
+        Ok(connection_string) => connection_string,
+        Err(_) => {
+            eprintln!("{} is not set; skipping", CONNECTION_STRING);
+            return;
+        }
+    };
+
+    let mut group = c.benchmark_group("put_message");
+    for &batch_size in BATCH_SIZES {
+        let store = RedisStore::new(
+            connection_string.as_str(),
+            "brizzo-bench".into(),
+            time::Duration::from_secs(60),
+            batch_size,
+            false,
+        )
+        .expect("failed to connect to Redis");
+
+        for text in TEXTS {
+            let message = |name: &str| {
+                Message::new(
+                    name,
+                    text,
+                    maze::Shape::Hex,
+                    initialize::LFSR::new(12345),
+                    &xid::Key::new(b"bench"),
+                )
+            };
+            let rooms = message("bench").rooms().count();
+            group.throughput(Throughput::Elements(rooms as u64));
+            group.bench_with_input(
+                BenchmarkId::new(format!("batch-{}", batch_size), rooms),
+                &rooms,
+                |b, _| {
+                    b.iter_batched(
+                        || {
+                            message(&format!(
+                                "bench-{}-{}",
+                                std::process::id(),
+                                COUNTER.fetch_add(1, atomic::Ordering::SeqCst),
+                            ))
+                        },
+                        |message| {
+                            store
+                                .put_message(&message, &Default::default())
+                                .unwrap()
+                        },
+                        BatchSize::SmallInput,
+                    )
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, put_message);
+criterion_main!(benches);
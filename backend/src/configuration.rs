@@ -1,11 +1,15 @@
 use std::fs;
 use std::io;
 use std::io::Read;
+use std::sync;
 use std::time;
 
 use actix_session::CookieSession;
 use toml;
 
+use crate::messages;
+use crate::messages::generator;
+use crate::messages::xid;
 use crate::store;
 
 #[derive(Clone, Deserialize, Serialize)]
@@ -16,8 +20,36 @@ pub struct Configuration {
     /// Session related configurations.
     session: Session,
 
-    /// Redis connection information.
-    redis: Redis,
+    /// Maze generation related configurations.
+    #[serde(default)]
+    generator: Generator,
+
+    /// Bounds for the lifetime of messages requested by clients.
+    #[serde(default)]
+    lifetime: Lifetime,
+
+    /// Lazy storage information; if present, only the recipes of messages
+    /// are stored.
+    ///
+    /// Room identifiers are derived again whenever a message is regenerated,
+    /// so this requires `generator.secret` to be set; otherwise changing the
+    /// session secret would invalidate all stored messages.
+    #[serde(default)]
+    lazy: Option<Lazy>,
+
+    /// Sliding expiry information; if present, reading a message extends
+    /// its lifetime.
+    #[serde(default)]
+    sliding: Option<Sliding>,
+
+    /// Room cache information; if present, recently read rooms are kept in
+    /// memory.
+    #[serde(default)]
+    cache: Option<Cache>,
+
+    /// Storage backend information.
+    #[serde(flatten)]
+    backend: Backend,
 }
 
 #[derive(Clone, Deserialize, Serialize)]
@@ -36,6 +68,104 @@ struct Session {
 
     /// The name of the cookie
     name: String,
+
+    /// The maximum age of the current room identifier, in milliseconds.
+    #[serde(default = "Session::default_max_age")]
+    max_age: u64,
+
+    /// Whether explorations are persisted so that they can be resumed.
+    #[serde(default)]
+    resume: bool,
+}
+
+impl Session {
+    /// The default maximum age of the current room identifier.
+    fn default_max_age() -> u64 {
+        xid::IdentifierCookie::DEFAULT_MAX_AGE.as_millis() as u64
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Generator {
+    /// The number of messages generated concurrently.
+    workers: usize,
+
+    /// The number of messages waiting to be generated before further
+    /// requests are rejected.
+    queue: usize,
+
+    /// The secret from which room identifiers are derived; if not present,
+    /// the session secret is used.
+    ///
+    /// This is required with lazy storage, since the rooms of stored messages
+    /// are regenerated using this secret.
+    #[serde(default)]
+    secret: Option<String>,
+}
+
+impl Default for Generator {
+    fn default() -> Self {
+        Self {
+            workers: 2,
+            queue: 16,
+            secret: None,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Lifetime {
+    /// The minimum time-to-live of a message, in milliseconds.
+    min_ttl: u64,
+
+    /// The maximum time-to-live of a message, in milliseconds.
+    max_ttl: u64,
+}
+
+impl Default for Lifetime {
+    fn default() -> Self {
+        Self {
+            min_ttl: 60 * 1000,
+            max_ttl: 31 * 24 * 60 * 60 * 1000,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Lazy {
+    /// The maximum number of regenerated messages kept in memory.
+    cache_size: usize,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Sliding {
+    /// The maximum lifetime of a message, in milliseconds.
+    max_lifetime: u64,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Cache {
+    /// The maximum number of cached rooms.
+    size: usize,
+
+    /// The maximum time for which a room is cached, in milliseconds.
+    ttl: u64,
+}
+
+/// The storage backend.
+///
+/// The backend is selected by the name of its configuration section.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Backend {
+    /// Redis connection information.
+    Redis(Redis),
+
+    /// In-memory store information.
+    Memory(Memory),
+
+    /// SQLite database information.
+    Sqlite(Sqlite),
 }
 
 #[derive(Clone, Deserialize, Serialize)]
@@ -43,8 +173,51 @@ struct Redis {
     /// The connection information.
     connection_string: String,
 
+    /// The prefix of all keys.
+    #[serde(default = "Redis::default_prefix")]
+    prefix: String,
+
     /// The TTL for records, in milliseconds.
     ttl: u64,
+
+    /// The maximum number of rooms written in a single pipeline.
+    #[serde(default = "Redis::default_batch_size")]
+    batch_size: usize,
+
+    /// Whether to fall back to reading messages stored under their raw
+    /// names, as done before keys were prefixed.
+    #[serde(default)]
+    legacy: bool,
+}
+
+impl Redis {
+    /// The default prefix of all keys.
+    fn default_prefix() -> String {
+        "brizzo".into()
+    }
+
+    /// The default maximum number of rooms written in a single pipeline.
+    fn default_batch_size() -> usize {
+        256
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Memory {
+    /// The TTL for records, in milliseconds.
+    ttl: u64,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Sqlite {
+    /// The path to the database file.
+    path: String,
+
+    /// The TTL for records, in milliseconds.
+    ttl: u64,
+
+    /// The interval between removals of expired records, in milliseconds.
+    sweep_interval: u64,
 }
 
 impl Configuration {
@@ -53,13 +226,34 @@ impl Configuration {
     /// # Arguments
     /// *  `path` - The path to the configuration file.
     pub fn load(path: &str) -> io::Result<Self> {
-        toml::from_str(&{
+        let configuration: Self = toml::from_str(&{
             let mut file = fs::File::open(path)?;
             let mut data = String::new();
             file.read_to_string(&mut data)?;
             data
         })
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        configuration.validate()?;
+        Ok(configuration)
+    }
+
+    /// Verifies that the settings are consistent.
+    fn validate(&self) -> io::Result<()> {
+        if self.lazy.is_some() && self.generator.secret.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "generator.secret must be set when lazy storage is enabled",
+            ));
+        }
+        if let Backend::Sqlite(sqlite) = &self.backend {
+            if sqlite.sweep_interval == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "sqlite.sweep_interval must be greater than zero",
+                ));
+            }
+        }
+        Ok(())
     }
 
     /// The bind string to which to listen.
@@ -74,11 +268,124 @@ impl Configuration {
             .name(&self.session.name)
     }
 
-    /// A store for values.
-    pub fn store(&self) -> Result<store::Store, store::Error> {
-        store::Store::new(
-            self.redis.connection_string.clone(),
-            time::Duration::from_millis(self.redis.ttl),
+    /// A pool of threads generating messages.
+    pub fn generator(&self) -> generator::Generator {
+        generator::Generator::new(
+            self.generator.workers,
+            self.generator.queue,
+            self.key(),
         )
     }
+
+    /// The key from which room identifiers are derived.
+    pub fn key(&self) -> xid::Key {
+        xid::Key::new(
+            self.generator
+                .secret
+                .as_ref()
+                .unwrap_or(&self.session.secret)
+                .as_bytes(),
+        )
+    }
+
+    /// Settings for exploring messages.
+    pub fn navigation(&self) -> messages::Navigation {
+        messages::Navigation {
+            max_age: time::Duration::from_millis(self.session.max_age),
+            resume: self.session.resume,
+            max_lifetime: self.sliding.as_ref().map(|sliding| {
+                time::Duration::from_millis(sliding.max_lifetime)
+            }),
+        }
+    }
+
+    /// Bounds for the lifetime of messages requested by clients.
+    pub fn lifetime(&self) -> messages::Lifetime {
+        messages::Lifetime {
+            min_ttl: time::Duration::from_millis(self.lifetime.min_ttl),
+            max_ttl: time::Duration::from_millis(self.lifetime.max_ttl),
+        }
+    }
+
+    /// A store for values.
+    ///
+    /// # Arguments
+    /// *  `generator` - The pool of threads generating messages, used to
+    ///    regenerate messages in lazy storage mode.
+    pub fn store(
+        &self,
+        generator: sync::Arc<generator::Generator>,
+    ) -> Result<sync::Arc<dyn store::Store>, store::Error> {
+        let store: sync::Arc<dyn store::Store> = match &self.backend {
+            Backend::Redis(redis) => sync::Arc::new(store::RedisStore::new(
+                redis.connection_string.clone(),
+                redis.prefix.clone(),
+                time::Duration::from_millis(redis.ttl),
+                redis.batch_size,
+                redis.legacy,
+            )?),
+            Backend::Memory(memory) => sync::Arc::new(store::MemoryStore::new(
+                time::Duration::from_millis(memory.ttl),
+            )),
+            Backend::Sqlite(sqlite) => sync::Arc::new(store::SqliteStore::new(
+                &sqlite.path,
+                time::Duration::from_millis(sqlite.ttl),
+                time::Duration::from_millis(sqlite.sweep_interval),
+            )?),
+        };
+        let store: sync::Arc<dyn store::Store> = match &self.lazy {
+            Some(lazy) => sync::Arc::new(store::LazyStore::new(
+                store,
+                lazy.cache_size,
+                generator,
+            )),
+            None => store,
+        };
+        let store: sync::Arc<dyn store::Store> = match &self.cache {
+            Some(cache) => store::CachedStore::new(
+                store,
+                cache.size,
+                time::Duration::from_millis(cache.ttl),
+            )?,
+            None => store,
+        };
+        Ok(store)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses a configuration with an SQLite backend.
+    ///
+    /// # Arguments
+    /// *  `sweep_interval` - The interval between removals of expired
+    ///    records, in milliseconds.
+    fn sqlite(sweep_interval: u64) -> Configuration {
+        toml::from_str(&format!(
+            r#"
+            [server]
+            bind = "127.0.0.1:8000"
+
+            [session]
+            secret = "secret"
+            secure = false
+            name = "session"
+
+            [sqlite]
+            path = ":memory:"
+            ttl = 60000
+            sweep_interval = {}
+            "#,
+            sweep_interval,
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn validate_sweep_interval() {
+        assert!(sqlite(1000).validate().is_ok());
+        assert!(sqlite(0).validate().is_err());
+    }
 }
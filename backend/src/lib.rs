@@ -0,0 +1,6 @@
+#[macro_use]
+extern crate serde;
+
+pub mod configuration;
+pub mod messages;
+pub mod store;
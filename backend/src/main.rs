@@ -1,16 +1,11 @@
-#[macro_use]
-extern crate serde;
-
 use std::env;
 use std::io;
 use std::sync;
 
-use actix_web::{App, HttpServer};
+use actix_web::{web, App, HttpServer};
 use env_logger;
 
-mod configuration;
-mod messages;
-mod store;
+use brizzo::{configuration, messages};
 
 #[actix_web::main]
 async fn main() -> io::Result<()> {
@@ -21,16 +16,34 @@ async fn main() -> io::Result<()> {
             .expect("BRIZZO_CONFIGURATION_FILE not set"),
     )?;
     let bind = configuration.server_bind();
-    let store = sync::Arc::new(sync::Mutex::new(configuration.store()?));
+    let generator = sync::Arc::new(configuration.generator());
+    let store = configuration.store(generator.clone())?;
+    let jobs = sync::Arc::new(messages::jobs::Jobs::default());
     HttpServer::new(move || {
         App::new()
             // Grant access to the store
             .data(store.clone())
+            // Grant access to the maze generator
+            .data(generator.clone())
+            // Grant access to the creation jobs
+            .data(jobs.clone())
+            // Grant access to the message lifetime bounds
+            .data(configuration.lifetime())
+            // Grant access to the navigation settings
+            .data(configuration.navigation())
+            // Grant access to the key used to encrypt room identifiers
+            .data(configuration.key())
+            // Report malformed request bodies like other API errors
+            .app_data(messages::error::json_config())
             // Persist session as a cookie
             .wrap(configuration.session())
-            .service(messages::create::handle)
-            .service(messages::read::handle)
-            .service(messages::update::handle)
+            .service(
+                web::scope("/api/v1")
+                    .service(messages::openapi::handle)
+                    .configure(messages::configure),
+            )
+            // Keep the unversioned paths as aliases
+            .configure(messages::configure)
     })
     .bind(bind)?
     .run()
@@ -1,5 +1,6 @@
 use std::fmt;
 use std::sync;
+use std::time;
 
 use actix_http::error::ResponseError;
 use actix_session::Session;
@@ -7,14 +8,21 @@ use actix_web::{http, post, web, HttpRequest, HttpResponse, Responder};
 
 use maze;
 use maze::initialize;
+use schemars::JsonSchema;
 
+use super::error::{self, ApiError};
+use super::generator;
+use super::jobs;
+use super::name;
+use super::openapi;
 use crate::store;
 
-/// The maximum length of a message.
-const MAX_LENGTH: usize = 64;
+/// The maximum length of a message, in characters.
+pub const MAX_LENGTH: usize = 64;
 
 /// A request to create a message.
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, JsonSchema, Serialize)]
+#[schemars(rename = "CreateRequest")]
 pub struct Request {
     /// The name of the message.
     name: String,
@@ -23,15 +31,64 @@ pub struct Request {
     text: String,
 
     /// The type of maze to generate.
+    #[schemars(with = "openapi::Shape")]
     shape: maze::Shape,
 
-    /// The random seed.
-    seed: initialize::LFSR,
+    /// The random seed, or `None` to let the server pick one.
+    #[serde(default)]
+    #[schemars(with = "Option<openapi::Seed>")]
+    seed: Option<initialize::LFSR>,
+
+    /// Whether room identifiers are encrypted per session, so that explorers
+    /// cannot share them.
+    #[serde(default)]
+    private: bool,
+
+    /// The time-to-live of the message, in milliseconds.
+    #[serde(default)]
+    ttl: Option<u64>,
+
+    /// The time at which the message expires, in milliseconds since the
+    /// epoch.
+    #[serde(default)]
+    expires_at: Option<u64>,
 }
 
 /// The response.
 #[derive(Debug)]
-pub struct Response(String);
+pub enum Response {
+    /// The message has been stored under a name.
+    Created {
+        /// The name of the message.
+        name: String,
+
+        /// The management token.
+        token: String,
+    },
+
+    /// The message will be created by a job.
+    Accepted {
+        /// The job identifier.
+        id: String,
+
+        /// The management token.
+        token: String,
+    },
+}
+
+/// The body of a successful response.
+#[derive(Debug, JsonSchema, Serialize)]
+#[schemars(rename = "Created")]
+pub struct Body {
+    /// The token required to manage the message.
+    ///
+    /// Only a hash of the token is stored, so it cannot be recovered.
+    token: String,
+
+    /// The status of the creation job, if the message is created by a job.
+    #[serde(flatten)]
+    status: Option<jobs::Status>,
+}
 
 /// The possible error values.
 #[derive(Debug)]
@@ -39,38 +96,142 @@ pub enum Error {
     /// The message is invalid.
     MessageInvalid,
 
+    /// The message contains characters that cannot be rendered.
+    UnsupportedCharacters(Vec<char>),
+
+    /// The message name is invalid.
+    NameInvalid(name::Error),
+
+    /// Both a time-to-live and an expiry time were requested, or the
+    /// expiry time has passed.
+    LifetimeInvalid,
+
     /// A message with the same name already exists.
     AlreadyExists,
 
+    /// Too many messages are being generated.
+    Busy,
+
+    /// The store cannot be reached.
+    StoreUnavailable,
+
     /// An internal error occurred.
     InternalError,
 }
 
 /// Creates a message.
 ///
+/// If the request carries the header `Prefer: respond-async`, the message is
+/// created by a background job, and the response is `202 Accepted` with the
+/// location of the job status.
+///
+/// The response body contains a token required to manage the message.
+///
 /// # Arguments
 /// *  `req` - A description of the message to create.
 #[post("/")]
 pub async fn handle(
+    request: HttpRequest,
     req: web::Json<Request>,
-    store: web::Data<sync::Arc<sync::Mutex<store::Store>>>,
+    store: web::Data<sync::Arc<dyn store::Store>>,
+    generator: web::Data<sync::Arc<generator::Generator>>,
+    jobs: web::Data<sync::Arc<jobs::Jobs>>,
+    lifetime: web::Data<super::Lifetime>,
     session: Session,
 ) -> impl Responder {
-    let mut store = store.lock()?;
-
-    if req.text.len() > MAX_LENGTH || req.text.len() < 1 {
+    let length = req.text.chars().count();
+    if length > MAX_LENGTH || length < 1 {
         log::info!("Invalid message: {}", req.text);
         Err(Error::MessageInvalid)
     } else {
-        if store.exists(&req.name)? {
-            Err(Error::AlreadyExists)
+        let unsupported = super::unsupported_characters(&req.text);
+        if !unsupported.is_empty() {
+            return Err(Error::UnsupportedCharacters(unsupported));
+        }
+
+        let req = req.into_inner();
+        let name = req.name.parse::<name::Name>()?;
+        if name.is_reserved() {
+            return Err(name::Error::Reserved.into());
+        }
+        let seed = req.seed.unwrap_or_else(super::generate_seed);
+        let token = super::generate_token();
+        let options = store::Options {
+            ttl: requested_ttl(&req, &lifetime)?,
+            owner: Some(super::hash_token(&token)),
+            private: req.private,
+        };
+        let store = store.get_ref().clone();
+
+        if respond_async(&request) {
+            let jobs = jobs.get_ref().clone();
+            let id = jobs.insert();
+            let location = location(&request, name.as_str());
+
+            let (job_jobs, job_id, message_name, key) =
+                (jobs.clone(), id.clone(), name.clone(), generator.key());
+            let pending = generator
+                .submit(move || {
+                    job_jobs.update(&job_id, jobs::Status::Generating);
+                    super::Message::new(
+                        message_name.as_str(),
+                        &req.text,
+                        req.shape,
+                        seed,
+                        &key,
+                    )
+                })
+                .map_err(|e| {
+                    // The client is told of the failure, so the job is not
+                    // worth keeping
+                    jobs.remove(&id);
+                    Error::from(e)
+                })?;
+
+            super::clear_id(&session, name.as_str());
+            actix_web::rt::spawn(async move {
+                let status = match pending.await {
+                    Ok(message) => {
+                        jobs.update(&id, jobs::Status::Storing);
+                        match super::block(move || {
+                            store
+                                .put_message(&message, &options)
+                                .map_err(Error::from)
+                        })
+                        .await
+                        {
+                            Ok(()) => {
+                                log::info!(
+                                    "Created message with location {}",
+                                    location,
+                                );
+                                jobs::Status::Done { location }
+                            }
+                            Err(e) => jobs::Status::Failed {
+                                error: e.to_string(),
+                            },
+                        }
+                    }
+                    Err(_) => jobs::Status::Failed {
+                        error: Error::InternalError.to_string(),
+                    },
+                };
+                jobs.update(&id, status);
+            });
+            Ok(Response::Accepted { id, token })
         } else {
-            let req = req.into_inner();
-            store.put_message(&super::Message::new(
-                &req.name, &req.text, req.shape, req.seed,
-            ))?;
-            super::clear_id(&session);
-            Ok(Response(req.name))
+            let message = generator
+                .generate(name.as_str().into(), req.text, req.shape, seed)
+                .await?;
+            super::block(move || {
+                store.put_message(&message, &options).map_err(Error::from)
+            })
+            .await?;
+            super::clear_id(&session, name.as_str());
+            Ok(Response::Created {
+                name: name.into(),
+                token,
+            })
         }
     }
 }
@@ -80,28 +241,109 @@ impl Responder for Response {
     type Future = HttpResponse;
 
     fn respond_to(self, request: &HttpRequest) -> Self::Future {
-        let url = format!(
-            "http://{}{}{}",
-            request
-                .headers()
-                .get("host")
-                .and_then(|h| h.to_str().ok())
-                .unwrap_or("host"),
-            request.uri().path(),
-            self.0,
-        );
-        log::info!("Created message with location {}", url);
-        HttpResponse::Created()
-            .header(http::header::LOCATION, url)
-            .finish()
+        match self {
+            Response::Created { name, token } => {
+                let url = location(request, &name);
+                log::info!("Created message with location {}", url);
+                HttpResponse::Created()
+                    .header(http::header::LOCATION, url)
+                    .json(Body {
+                        token,
+                        status: None,
+                    })
+            }
+            Response::Accepted { id, token } => {
+                let url = location(request, &format!("jobs/{}", id));
+                log::info!("Accepted job with location {}", url);
+                HttpResponse::Accepted()
+                    .header(http::header::LOCATION, url)
+                    .json(Body {
+                        token,
+                        status: Some(jobs::Status::Queued),
+                    })
+            }
+        }
     }
 }
 
+/// Determines the time-to-live requested for a message.
+///
+/// If neither a time-to-live nor an expiry time is requested, `None` is
+/// returned; otherwise the result is clamped to the configured bounds. An
+/// expiry time that has already passed is rejected rather than clamped.
+///
+/// # Arguments
+/// *  `req` - The request.
+/// *  `lifetime` - The bounds for the lifetime of messages.
+fn requested_ttl(
+    req: &Request,
+    lifetime: &super::Lifetime,
+) -> Result<Option<time::Duration>, Error> {
+    let ttl = match (req.ttl, req.expires_at) {
+        (Some(_), Some(_)) => return Err(Error::LifetimeInvalid),
+        (Some(ttl), None) => time::Duration::from_millis(ttl),
+        (None, Some(expires_at)) => time::UNIX_EPOCH
+            .checked_add(time::Duration::from_millis(expires_at))
+            .and_then(|t| t.duration_since(time::SystemTime::now()).ok())
+            .filter(|ttl| !ttl.is_zero())
+            .ok_or(Error::LifetimeInvalid)?,
+        (None, None) => return Ok(None),
+    };
+    Ok(Some(lifetime.clamp(ttl)))
+}
+
+/// Determines whether the client prefers an asynchronous response.
+///
+/// # Arguments
+/// *  `request` - The request.
+fn respond_async(request: &HttpRequest) -> bool {
+    request
+        .headers()
+        .get_all("prefer")
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .any(|p| p.trim().eq_ignore_ascii_case("respond-async"))
+}
+
+/// Generates the URL of a resource relative to the request path.
+///
+/// # Arguments
+/// *  `request` - The request.
+/// *  `path` - The path of the resource.
+fn location(request: &HttpRequest, path: &str) -> String {
+    format!(
+        "http://{}{}{}",
+        request
+            .headers()
+            .get("host")
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or("host"),
+        request.uri().path(),
+        path,
+    )
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Error::MessageInvalid => write!(f, "message invalid"),
+            Error::NameInvalid(e) => write!(f, "name invalid: {}", e),
+            Error::LifetimeInvalid => write!(
+                f,
+                "only one of ttl and expires_at may be given, and expires_at \
+                 must be in the future",
+            ),
+            Error::UnsupportedCharacters(cs) => write!(
+                f,
+                "unsupported characters: {}",
+                cs.iter()
+                    .map(|c| format!("{:?}", c))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ),
             Error::AlreadyExists => write!(f, "already exists"),
+            Error::Busy => write!(f, "busy"),
+            Error::StoreUnavailable => write!(f, "store unavailable"),
             Error::InternalError => write!(f, "internal error"),
         }
     }
@@ -110,21 +352,162 @@ impl fmt::Display for Error {
 impl ResponseError for Error {
     fn status_code(&self) -> http::StatusCode {
         match self {
-            Error::MessageInvalid => http::StatusCode::BAD_REQUEST,
+            Error::MessageInvalid => http::StatusCode::UNPROCESSABLE_ENTITY,
+            Error::NameInvalid(_) => http::StatusCode::BAD_REQUEST,
+            Error::LifetimeInvalid => http::StatusCode::UNPROCESSABLE_ENTITY,
+            Error::UnsupportedCharacters(_) => {
+                http::StatusCode::UNPROCESSABLE_ENTITY
+            }
             Error::AlreadyExists => http::StatusCode::CONFLICT,
+            Error::Busy => http::StatusCode::SERVICE_UNAVAILABLE,
+            Error::StoreUnavailable => http::StatusCode::SERVICE_UNAVAILABLE,
             Error::InternalError => http::StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
+
+    fn error_response(&self) -> HttpResponse {
+        error::respond(self)
+    }
+}
+
+impl ApiError for Error {
+    fn all() -> Vec<Self> {
+        vec![
+            Error::MessageInvalid,
+            Error::UnsupportedCharacters(Vec::new()),
+            Error::NameInvalid(name::Error::TooShort),
+            Error::LifetimeInvalid,
+            Error::AlreadyExists,
+            Error::Busy,
+            Error::StoreUnavailable,
+            Error::InternalError,
+        ]
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            Error::MessageInvalid => "message_invalid",
+            Error::NameInvalid(_) => "name_invalid",
+            Error::LifetimeInvalid => "lifetime_invalid",
+            Error::UnsupportedCharacters(_) => "unsupported_characters",
+            Error::AlreadyExists => "already_exists",
+            Error::Busy => "busy",
+            Error::StoreUnavailable => "store_unavailable",
+            Error::InternalError => "internal_error",
+        }
+    }
+
+    fn details(&self) -> Option<serde_json::Value> {
+        match self {
+            Error::NameInvalid(e) => error::name_details(e),
+            Error::UnsupportedCharacters(cs) => Some(error::characters(cs)),
+            _ => None,
+        }
+    }
+}
+
+impl From<generator::Error> for Error {
+    fn from(source: generator::Error) -> Self {
+        match source {
+            generator::Error::Busy => Self::Busy,
+            generator::Error::Unavailable => Self::InternalError,
+        }
+    }
+}
+
+impl From<name::Error> for Error {
+    fn from(source: name::Error) -> Self {
+        Self::NameInvalid(source)
+    }
 }
 
 impl From<store::Error> for Error {
-    fn from(_source: store::Error) -> Self {
-        Self::InternalError
+    fn from(source: store::Error) -> Self {
+        match source {
+            store::Error::Connection => Self::StoreUnavailable,
+            store::Error::Exists => Self::AlreadyExists,
+            _ => Self::InternalError,
+        }
     }
 }
 
-impl<T> From<sync::PoisonError<T>> for Error {
-    fn from(_source: sync::PoisonError<T>) -> Self {
-        Self::InternalError
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages;
+
+    /// The bounds used by the tests.
+    fn lifetime() -> messages::Lifetime {
+        messages::Lifetime {
+            min_ttl: time::Duration::from_secs(60),
+            max_ttl: time::Duration::from_secs(3600),
+        }
+    }
+
+    /// A request with a lifetime.
+    ///
+    /// # Arguments
+    /// *  `ttl` - The requested time-to-live, in milliseconds.
+    /// *  `expires_at` - The requested expiry time, in milliseconds since
+    ///    the epoch.
+    fn request(ttl: Option<u64>, expires_at: Option<u64>) -> Request {
+        Request {
+            name: "test".into(),
+            text: "A".into(),
+            shape: maze::Shape::Hex,
+            seed: None,
+            private: false,
+            ttl,
+            expires_at,
+        }
+    }
+
+    /// The current time, in milliseconds since the epoch.
+    fn now() -> u64 {
+        time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+    }
+
+    #[test]
+    fn ttl_defaults_to_store() {
+        assert!(requested_ttl(&request(None, None), &lifetime())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn ttl_is_clamped() {
+        let lifetime = lifetime();
+        let ttl = |ttl| requested_ttl(&request(Some(ttl), None), &lifetime);
+        assert_eq!(ttl(1).unwrap(), Some(lifetime.min_ttl));
+        assert_eq!(ttl(120_000).unwrap(), Some(time::Duration::from_secs(120)));
+        assert_eq!(ttl(u64::MAX).unwrap(), Some(lifetime.max_ttl));
+    }
+
+    #[test]
+    fn expiry_is_clamped() {
+        let lifetime = lifetime();
+        let expires_at =
+            |at| requested_ttl(&request(None, Some(at)), &lifetime);
+        let ttl = expires_at(now() + 120_000).unwrap().unwrap();
+        assert!(ttl > time::Duration::from_secs(110));
+        assert!(ttl <= time::Duration::from_secs(120));
+        assert_eq!(expires_at(now() + 1000).unwrap(), Some(lifetime.min_ttl),);
+        assert_eq!(expires_at(u64::MAX).unwrap(), Some(lifetime.max_ttl));
+    }
+
+    #[test]
+    fn invalid_lifetimes_are_rejected() {
+        let lifetime = lifetime();
+        assert!(matches!(
+            requested_ttl(&request(None, Some(now() - 1000)), &lifetime),
+            Err(Error::LifetimeInvalid)
+        ));
+        assert!(matches!(
+            requested_ttl(&request(Some(1000), Some(now() + 1000)), &lifetime),
+            Err(Error::LifetimeInvalid)
+        ));
     }
 }
@@ -0,0 +1,164 @@
+use std::fmt;
+use std::sync;
+
+use actix_http::error::ResponseError;
+use actix_session::Session;
+use actix_web::{delete, http, web, HttpRequest, HttpResponse, Responder};
+
+use super::error::{self, ApiError};
+use super::name;
+use crate::store;
+
+/// The parameters passed in the path.
+#[derive(Deserialize)]
+pub struct Path {
+    /// The name of the message.
+    message_name: String,
+}
+
+/// The possible error values.
+#[derive(Debug)]
+pub enum Error {
+    /// The message is unknown.
+    UnknownMessage,
+
+    /// The message name is invalid.
+    NameInvalid(name::Error),
+
+    /// No management token was passed.
+    Unauthorized,
+
+    /// The management token is invalid.
+    Forbidden,
+
+    /// The message is being created or modified by another request.
+    Conflict,
+
+    /// The store cannot be reached.
+    StoreUnavailable,
+
+    /// An internal error occurred.
+    InternalError,
+}
+
+/// Removes a message before it expires.
+///
+/// The management token returned when the message was created must be
+/// passed as a bearer token in the `Authorization` header.
+#[delete("/{message_name}")]
+pub async fn handle(
+    request: HttpRequest,
+    path: web::Path<Path>,
+    store: web::Data<sync::Arc<dyn store::Store>>,
+    session: Session,
+) -> impl Responder {
+    let name = path.message_name.parse::<name::Name>()?;
+    let token = super::bearer_token(&request).ok_or(Error::Unauthorized)?;
+
+    let store = store.get_ref().clone();
+    let message_name = name.clone();
+    super::block(move || -> Result<_, Error> {
+        if !store.exists(message_name.as_str())? {
+            return Err(Error::UnknownMessage);
+        }
+
+        let owner = super::hash_token(&token);
+        if store.get_owner(message_name.as_str())?.as_ref() != Some(&owner) {
+            return Err(Error::Forbidden);
+        }
+
+        // The owner is checked again by the store, in case the message was
+        // replaced since
+        if store.delete(message_name.as_str(), &owner)? {
+            Ok(())
+        } else {
+            Err(Error::UnknownMessage)
+        }
+    })
+    .await?;
+
+    log::info!("Deleted message {}", name);
+    super::clear_id(&session, name.as_str());
+    Ok::<_, Error>(HttpResponse::NoContent().finish())
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UnknownMessage => write!(f, "unknown message"),
+            Error::NameInvalid(e) => write!(f, "name invalid: {}", e),
+            Error::Unauthorized => write!(f, "management token missing"),
+            Error::Forbidden => write!(f, "management token invalid"),
+            Error::Conflict => write!(f, "message is being modified"),
+            Error::StoreUnavailable => write!(f, "store unavailable"),
+            Error::InternalError => write!(f, "internal error"),
+        }
+    }
+}
+
+impl ResponseError for Error {
+    fn status_code(&self) -> http::StatusCode {
+        match self {
+            Error::UnknownMessage => http::StatusCode::NOT_FOUND,
+            Error::NameInvalid(_) => http::StatusCode::BAD_REQUEST,
+            Error::Unauthorized => http::StatusCode::UNAUTHORIZED,
+            Error::Forbidden => http::StatusCode::FORBIDDEN,
+            Error::Conflict => http::StatusCode::CONFLICT,
+            Error::StoreUnavailable => http::StatusCode::SERVICE_UNAVAILABLE,
+            Error::InternalError => http::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        error::respond(self)
+    }
+}
+
+impl ApiError for Error {
+    fn all() -> Vec<Self> {
+        vec![
+            Error::UnknownMessage,
+            Error::NameInvalid(name::Error::TooShort),
+            Error::Unauthorized,
+            Error::Forbidden,
+            Error::Conflict,
+            Error::StoreUnavailable,
+            Error::InternalError,
+        ]
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            Error::UnknownMessage => "unknown_message",
+            Error::NameInvalid(_) => "name_invalid",
+            Error::Unauthorized => "unauthorized",
+            Error::Forbidden => "forbidden",
+            Error::Conflict => "conflict",
+            Error::StoreUnavailable => "store_unavailable",
+            Error::InternalError => "internal_error",
+        }
+    }
+
+    fn details(&self) -> Option<serde_json::Value> {
+        match self {
+            Error::NameInvalid(e) => error::name_details(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<name::Error> for Error {
+    fn from(source: name::Error) -> Self {
+        Self::NameInvalid(source)
+    }
+}
+
+impl From<store::Error> for Error {
+    fn from(source: store::Error) -> Self {
+        match source {
+            store::Error::Connection => Self::StoreUnavailable,
+            store::Error::Exists => Self::Conflict,
+            _ => Self::InternalError,
+        }
+    }
+}
@@ -0,0 +1,275 @@
+use std::fmt;
+use std::sync;
+
+use actix_http::error::ResponseError;
+use actix_session::Session;
+use actix_web::{http, patch, web, HttpRequest, HttpResponse, Responder};
+
+use maze;
+use maze::initialize;
+use schemars::JsonSchema;
+
+use super::create::MAX_LENGTH;
+use super::error::{self, ApiError};
+use super::generator;
+use super::name;
+use super::openapi;
+use crate::store;
+
+/// The parameters passed in the path.
+#[derive(Deserialize)]
+pub struct Path {
+    /// The name of the message.
+    message_name: String,
+}
+
+/// A request to regenerate a message.
+///
+/// Omitted values are taken from the recipe of the message.
+#[derive(Deserialize, JsonSchema, Serialize)]
+#[schemars(rename = "EditRequest")]
+pub struct Request {
+    /// The new text.
+    #[serde(default)]
+    text: Option<String>,
+
+    /// The new type of maze.
+    #[serde(default)]
+    #[schemars(with = "Option<openapi::Shape>")]
+    shape: Option<maze::Shape>,
+
+    /// The new random seed.
+    #[serde(default)]
+    #[schemars(with = "Option<openapi::Seed>")]
+    seed: Option<initialize::LFSR>,
+}
+
+/// The possible error values.
+#[derive(Debug)]
+pub enum Error {
+    /// The message is unknown.
+    UnknownMessage,
+
+    /// The message name is invalid.
+    NameInvalid(name::Error),
+
+    /// The new text is invalid.
+    MessageInvalid,
+
+    /// The new text contains characters that cannot be rendered.
+    UnsupportedCharacters(Vec<char>),
+
+    /// The message has no stored recipe, and not all values were given.
+    Incomplete,
+
+    /// No management token was passed.
+    Unauthorized,
+
+    /// The management token is invalid.
+    Forbidden,
+
+    /// The message is being created or modified by another request.
+    Conflict,
+
+    /// Too many messages are being generated.
+    Busy,
+
+    /// The store cannot be reached.
+    StoreUnavailable,
+
+    /// An internal error occurred.
+    InternalError,
+}
+
+/// Regenerates a message under the same name.
+///
+/// The management token returned when the message was created must be
+/// passed as a bearer token in the `Authorization` header. The message keeps
+/// its name, owner and expiry time, and all its rooms are replaced at once.
+///
+/// # Arguments
+/// *  `req` - The values to change.
+#[patch("/{message_name}")]
+pub async fn handle(
+    request: HttpRequest,
+    path: web::Path<Path>,
+    req: web::Json<Request>,
+    store: web::Data<sync::Arc<dyn store::Store>>,
+    generator: web::Data<sync::Arc<generator::Generator>>,
+    session: Session,
+) -> impl Responder {
+    let name = path.message_name.parse::<name::Name>()?;
+    let token = super::bearer_token(&request).ok_or(Error::Unauthorized)?;
+    if let Some(text) = &req.text {
+        let length = text.chars().count();
+        if length > MAX_LENGTH || length < 1 {
+            log::info!("Invalid message: {}", text);
+            return Err(Error::MessageInvalid);
+        }
+        let unsupported = super::unsupported_characters(text);
+        if !unsupported.is_empty() {
+            return Err(Error::UnsupportedCharacters(unsupported));
+        }
+    }
+
+    let store = store.get_ref().clone();
+    let (owner_store, message_name) = (store.clone(), name.clone());
+    let (owner, recipe) = super::block(move || -> Result<_, Error> {
+        if !owner_store.exists(message_name.as_str())? {
+            return Err(Error::UnknownMessage);
+        }
+
+        match owner_store.get_owner(message_name.as_str())? {
+            Some(owner) if owner == super::hash_token(&token) => {
+                let recipe = owner_store.get_recipe(message_name.as_str())?;
+                Ok((owner, recipe))
+            }
+            _ => Err(Error::Forbidden),
+        }
+    })
+    .await?;
+
+    let req = req.into_inner();
+    let (text, shape, seed) = match (req.text, req.shape, req.seed, recipe) {
+        (Some(text), Some(shape), Some(seed), _) => (text, shape, seed),
+        (text, shape, seed, Some(recipe)) => (
+            text.unwrap_or(recipe.text),
+            shape.unwrap_or(recipe.shape),
+            seed.unwrap_or(recipe.seed),
+        ),
+        _ => return Err(Error::Incomplete),
+    };
+
+    let message = generator
+        .generate(name.as_str().into(), text, shape, seed)
+        .await?;
+    // The message may have been deleted and created again by someone else
+    // while it was being generated
+    super::block(move || {
+        store.replace_message(&message, &owner).map_err(Error::from)
+    })
+    .await?;
+
+    log::info!("Regenerated message {}", name);
+    super::clear_id(&session, name.as_str());
+    Ok(HttpResponse::NoContent().finish())
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UnknownMessage => write!(f, "unknown message"),
+            Error::NameInvalid(e) => write!(f, "name invalid: {}", e),
+            Error::MessageInvalid => write!(f, "message invalid"),
+            Error::UnsupportedCharacters(cs) => write!(
+                f,
+                "unsupported characters: {}",
+                cs.iter()
+                    .map(|c| format!("{:?}", c))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ),
+            Error::Incomplete => {
+                write!(f, "text, shape and seed are required")
+            }
+            Error::Unauthorized => write!(f, "management token missing"),
+            Error::Forbidden => write!(f, "management token invalid"),
+            Error::Conflict => write!(f, "message is being modified"),
+            Error::Busy => write!(f, "busy"),
+            Error::StoreUnavailable => write!(f, "store unavailable"),
+            Error::InternalError => write!(f, "internal error"),
+        }
+    }
+}
+
+impl ResponseError for Error {
+    fn status_code(&self) -> http::StatusCode {
+        match self {
+            Error::UnknownMessage => http::StatusCode::NOT_FOUND,
+            Error::NameInvalid(_) => http::StatusCode::BAD_REQUEST,
+            Error::MessageInvalid => http::StatusCode::UNPROCESSABLE_ENTITY,
+            Error::UnsupportedCharacters(_) => {
+                http::StatusCode::UNPROCESSABLE_ENTITY
+            }
+            Error::Incomplete => http::StatusCode::UNPROCESSABLE_ENTITY,
+            Error::Unauthorized => http::StatusCode::UNAUTHORIZED,
+            Error::Forbidden => http::StatusCode::FORBIDDEN,
+            Error::Conflict => http::StatusCode::CONFLICT,
+            Error::Busy => http::StatusCode::SERVICE_UNAVAILABLE,
+            Error::StoreUnavailable => http::StatusCode::SERVICE_UNAVAILABLE,
+            Error::InternalError => http::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        error::respond(self)
+    }
+}
+
+impl ApiError for Error {
+    fn all() -> Vec<Self> {
+        vec![
+            Error::UnknownMessage,
+            Error::NameInvalid(name::Error::TooShort),
+            Error::MessageInvalid,
+            Error::UnsupportedCharacters(Vec::new()),
+            Error::Incomplete,
+            Error::Unauthorized,
+            Error::Forbidden,
+            Error::Conflict,
+            Error::Busy,
+            Error::StoreUnavailable,
+            Error::InternalError,
+        ]
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            Error::UnknownMessage => "unknown_message",
+            Error::NameInvalid(_) => "name_invalid",
+            Error::MessageInvalid => "message_invalid",
+            Error::UnsupportedCharacters(_) => "unsupported_characters",
+            Error::Incomplete => "incomplete",
+            Error::Unauthorized => "unauthorized",
+            Error::Forbidden => "forbidden",
+            Error::Conflict => "conflict",
+            Error::Busy => "busy",
+            Error::StoreUnavailable => "store_unavailable",
+            Error::InternalError => "internal_error",
+        }
+    }
+
+    fn details(&self) -> Option<serde_json::Value> {
+        match self {
+            Error::NameInvalid(e) => error::name_details(e),
+            Error::UnsupportedCharacters(cs) => Some(error::characters(cs)),
+            _ => None,
+        }
+    }
+}
+
+impl From<generator::Error> for Error {
+    fn from(source: generator::Error) -> Self {
+        match source {
+            generator::Error::Busy => Self::Busy,
+            generator::Error::Unavailable => Self::InternalError,
+        }
+    }
+}
+
+impl From<name::Error> for Error {
+    fn from(source: name::Error) -> Self {
+        Self::NameInvalid(source)
+    }
+}
+
+impl From<store::Error> for Error {
+    fn from(source: store::Error) -> Self {
+        match source {
+            store::Error::Connection => Self::StoreUnavailable,
+            store::Error::Exists => Self::Conflict,
+            store::Error::Missing => Self::UnknownMessage,
+            _ => Self::InternalError,
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use std::fmt;
+
+use actix_http::error::ResponseError;
+use actix_web::{error, http, web, HttpResponse};
+use schemars::JsonSchema;
+
+use super::name;
+
+/// An error reported to clients.
+///
+/// All handler errors implement this trait, and respond with a JSON body
+/// generated by [`respond`].
+pub trait ApiError: ResponseError {
+    /// One error of every kind, from which the documented responses are
+    /// derived.
+    fn all() -> Vec<Self>
+    where
+        Self: Sized;
+
+    /// A stable, machine readable code identifying the kind of error.
+    fn code(&self) -> &'static str;
+
+    /// Additional information about the error.
+    fn details(&self) -> Option<serde_json::Value> {
+        None
+    }
+}
+
+/// The body of an error response.
+#[derive(Debug, JsonSchema, Serialize)]
+#[schemars(rename = "Error")]
+pub struct Body {
+    /// A stable, machine readable code identifying the kind of error.
+    code: &'static str,
+
+    /// A human readable description of the error.
+    message: String,
+
+    /// Additional information about the error.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<serde_json::Value>,
+}
+
+/// Generates the response for an error.
+///
+/// # Arguments
+/// *  `error` - The error.
+pub fn respond<E>(error: &E) -> HttpResponse
+where
+    E: ApiError,
+{
+    HttpResponse::build(error.status_code()).json(Body {
+        code: error.code(),
+        message: error.to_string(),
+        details: error.details(),
+    })
+}
+
+/// Generates the details of an error caused by characters in the request.
+///
+/// # Arguments
+/// *  `characters` - The offending characters.
+pub fn characters(characters: &[char]) -> serde_json::Value {
+    serde_json::json!({ "characters": characters })
+}
+
+/// Generates the details of a name error.
+///
+/// # Arguments
+/// *  `error` - The name error.
+pub fn name_details(error: &name::Error) -> Option<serde_json::Value> {
+    match error {
+        name::Error::InvalidCharacter(c) => Some(characters(&[*c])),
+        _ => None,
+    }
+}
+
+/// A request body that could not be parsed.
+#[derive(Debug)]
+pub struct RequestInvalid(String);
+
+impl fmt::Display for RequestInvalid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "request invalid: {}", self.0)
+    }
+}
+
+impl ResponseError for RequestInvalid {
+    fn status_code(&self) -> http::StatusCode {
+        http::StatusCode::BAD_REQUEST
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        respond(self)
+    }
+}
+
+impl ApiError for RequestInvalid {
+    fn all() -> Vec<Self> {
+        vec![RequestInvalid(String::new())]
+    }
+
+    fn code(&self) -> &'static str {
+        "request_invalid"
+    }
+}
+
+/// A JSON extractor configuration reporting unparsable request bodies as
+/// [`RequestInvalid`].
+pub fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default()
+        .error_handler(|e, _| error::Error::from(RequestInvalid(e.to_string())))
+}
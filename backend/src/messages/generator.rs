@@ -0,0 +1,234 @@
+use std::fmt;
+use std::panic;
+use std::sync;
+use std::sync::mpsc;
+use std::thread;
+
+use futures::channel::oneshot;
+
+use maze::initialize;
+
+use super::xid;
+use super::Message;
+
+/// A unit of work for the pool.
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Errors relating to the generator.
+#[derive(Copy, Clone, Debug)]
+pub enum Error {
+    /// All workers are busy and the queue is full.
+    Busy,
+
+    /// The workers have terminated.
+    Unavailable,
+}
+
+/// A bounded pool of threads generating messages.
+///
+/// Generating a maze is CPU bound, so it is kept off the executor threads
+/// that serve navigation requests.
+pub struct Generator {
+    /// The queue of pending jobs.
+    sender: sync::Mutex<mpsc::SyncSender<Job>>,
+
+    /// The key from which room identifiers are derived.
+    key: sync::Arc<xid::Key>,
+}
+
+impl Generator {
+    /// Creates a new generator and starts its workers.
+    ///
+    /// # Arguments
+    /// *  `workers` - The number of messages generated concurrently.
+    /// *  `queue` - The number of messages waiting for a worker before
+    ///    further requests are rejected.
+    /// *  `key` - The key from which room identifiers are derived.
+    pub fn new(workers: usize, queue: usize, key: xid::Key) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(queue);
+        let receiver = sync::Arc::new(sync::Mutex::new(receiver));
+        for _ in 0..workers.max(1) {
+            let receiver = receiver.clone();
+            thread::spawn(move || loop {
+                let job = match receiver.lock() {
+                    Ok(receiver) => receiver.recv(),
+                    Err(_) => break,
+                };
+                match job {
+                    // A panicking job must not take its worker down with it;
+                    // its caller sees the result channel closed
+                    Ok(job) => {
+                        if panic::catch_unwind(panic::AssertUnwindSafe(job))
+                            .is_err()
+                        {
+                            log::error!("A generator job panicked");
+                        }
+                    }
+                    Err(_) => break,
+                }
+            });
+        }
+
+        Self {
+            sender: sync::Mutex::new(sender),
+            key: sync::Arc::new(key),
+        }
+    }
+
+    /// The key from which room identifiers are derived.
+    pub fn key(&self) -> sync::Arc<xid::Key> {
+        self.key.clone()
+    }
+
+    /// Generates a message on a worker thread.
+    ///
+    /// This fails immediately with [`Error::Busy`] if the queue is full.
+    ///
+    /// # Arguments
+    /// *  `name` - The name of the message.
+    /// *  `text` - The actual text.
+    /// *  `shape` - The type of maze to generate.
+    /// *  `seed` - The random seed.
+    pub async fn generate(
+        &self,
+        name: String,
+        text: String,
+        shape: maze::Shape,
+        seed: initialize::LFSR,
+    ) -> Result<Message, Error> {
+        let key = self.key();
+        self.submit(move || Message::new(&name, &text, shape, seed, &key))?
+            .await
+            .map_err(|_| Error::Unavailable)
+    }
+
+    /// Queues a job for a worker thread.
+    ///
+    /// The job is queued before this function returns, so a full queue is
+    /// reported immediately with [`Error::Busy`]. The returned receiver
+    /// resolves to the result of the job once a worker has run it.
+    ///
+    /// # Arguments
+    /// *  `f` - The job to run.
+    pub fn submit<F, T>(&self, f: F) -> Result<oneshot::Receiver<T>, Error>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (job, rx) = job(f);
+        self.sender
+            .lock()
+            .map_err(|_| Error::Unavailable)?
+            .try_send(job)
+            .map_err(|e| match e {
+                mpsc::TrySendError::Full(_) => Error::Busy,
+                mpsc::TrySendError::Disconnected(_) => Error::Unavailable,
+            })?;
+
+        Ok(rx)
+    }
+
+    /// Queues a job for a worker thread, waiting for room in the queue if it
+    /// is full.
+    ///
+    /// This blocks the calling thread, so it must not be called on the
+    /// executor threads. It is intended for work that cannot be turned away,
+    /// such as regenerating a message that is being read.
+    ///
+    /// # Arguments
+    /// *  `f` - The job to run.
+    pub fn queue<F, T>(&self, f: F) -> Result<oneshot::Receiver<T>, Error>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (job, rx) = job(f);
+
+        // Wait on a clone of the sender, so that other callers are not
+        // blocked while the queue is full
+        let sender =
+            self.sender.lock().map_err(|_| Error::Unavailable)?.clone();
+        sender.send(job).map_err(|_| Error::Unavailable)?;
+
+        Ok(rx)
+    }
+}
+
+/// Wraps a function in a job sending its result to a receiver.
+///
+/// # Arguments
+/// *  `f` - The function to run.
+fn job<F, T>(f: F) -> (Job, oneshot::Receiver<T>)
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    let job: Job = Box::new(move || {
+        let _ = tx.send(f());
+    });
+    (job, rx)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Busy => write!(f, "busy"),
+            Error::Unavailable => write!(f, "unavailable"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor;
+
+    use super::*;
+
+    #[test]
+    fn submit_runs_jobs() {
+        let generator = Generator::new(2, 4, xid::Key::new(b"test"));
+        let results = (0..4)
+            .map(|i| generator.submit(move || i * 2).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            results
+                .into_iter()
+                .map(|rx| executor::block_on(rx).unwrap())
+                .collect::<Vec<_>>(),
+            vec![0, 2, 4, 6],
+        );
+    }
+
+    #[test]
+    fn submit_fails_when_full() {
+        let generator = Generator::new(1, 1, xid::Key::new(b"test"));
+
+        // Keep the only worker busy, and wait until it has taken its job
+        let (started, running) = mpsc::channel();
+        let (release, blocked) = mpsc::channel::<()>();
+        generator
+            .submit(move || {
+                started.send(()).unwrap();
+                blocked.recv().unwrap()
+            })
+            .unwrap();
+        running.recv().unwrap();
+
+        let queued = generator.submit(|| 1).unwrap();
+        assert!(matches!(generator.submit(|| 2), Err(Error::Busy)));
+
+        release.send(()).unwrap();
+        assert_eq!(executor::block_on(queued).unwrap(), 1);
+    }
+
+    #[test]
+    fn workers_survive_panics() {
+        let generator = Generator::new(1, 4, xid::Key::new(b"test"));
+
+        let panicked = generator.submit(|| -> u32 { panic!("job failed") });
+        assert!(executor::block_on(panicked.unwrap()).is_err());
+        let next = generator.submit(|| 1).unwrap();
+        assert_eq!(executor::block_on(next).unwrap(), 1);
+    }
+}
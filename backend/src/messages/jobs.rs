@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync;
+use std::time;
+
+use actix_http::error::ResponseError;
+use actix_web::{get, http, web, HttpResponse, Responder};
+use schemars::JsonSchema;
+
+use super::error::{self, ApiError};
+
+/// The time for which the status of a job is kept after its last change.
+const RETENTION: time::Duration = time::Duration::from_secs(10 * 60);
+
+/// The parameters passed in the path.
+#[derive(Deserialize)]
+pub struct Path {
+    /// The job identifier.
+    id: String,
+}
+
+/// The state of a creation job.
+#[derive(Clone, Debug, JsonSchema, Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+#[schemars(rename = "JobStatus")]
+pub enum Status {
+    /// The job is waiting for a generator worker.
+    Queued,
+
+    /// The maze is being generated.
+    Generating,
+
+    /// The rooms are being written to the store.
+    Storing,
+
+    /// The message has been stored.
+    Done {
+        /// The location of the message.
+        location: String,
+    },
+
+    /// The message could not be created.
+    Failed {
+        /// A description of the failure.
+        error: String,
+    },
+}
+
+/// The possible error values.
+#[derive(Debug)]
+pub enum Error {
+    /// The job is unknown or has expired.
+    UnknownJob,
+}
+
+/// A registry of creation jobs.
+///
+/// Jobs are kept in process memory, so their status is only available from
+/// the server that accepted them.
+#[derive(Default)]
+pub struct Jobs {
+    /// The jobs and the time of their last status change, keyed on
+    /// identifier.
+    jobs: sync::Mutex<HashMap<String, (time::Instant, Status)>>,
+}
+
+impl Jobs {
+    /// Registers a new queued job and returns its identifier.
+    pub fn insert(&self) -> String {
+        let id = super::generate_token();
+        let now = time::Instant::now();
+        if let Ok(mut jobs) = self.jobs.lock() {
+            jobs.retain(|_, (updated, _)| {
+                now.duration_since(*updated) < RETENTION
+            });
+            jobs.insert(id.clone(), (now, Status::Queued));
+        }
+        id
+    }
+
+    /// Updates the status of a job.
+    ///
+    /// # Arguments
+    /// *  `id` - The job identifier.
+    /// *  `status` - The new status.
+    pub fn update(&self, id: &str, status: Status) {
+        if let Ok(mut jobs) = self.jobs.lock() {
+            jobs.insert(id.into(), (time::Instant::now(), status));
+        }
+    }
+
+    /// Forgets a job that was never started.
+    ///
+    /// # Arguments
+    /// *  `id` - The job identifier.
+    pub fn remove(&self, id: &str) {
+        if let Ok(mut jobs) = self.jobs.lock() {
+            jobs.remove(id);
+        }
+    }
+
+    /// Retrieves the status of a job.
+    ///
+    /// # Arguments
+    /// *  `id` - The job identifier.
+    pub fn get(&self, id: &str) -> Option<Status> {
+        self.jobs
+            .lock()
+            .ok()?
+            .get(id)
+            .filter(|(updated, _)| updated.elapsed() < RETENTION)
+            .map(|(_, status)| status.clone())
+    }
+}
+
+/// Reads the status of a creation job.
+#[get("/jobs/{id}")]
+pub async fn handle(
+    path: web::Path<Path>,
+    jobs: web::Data<sync::Arc<Jobs>>,
+) -> impl Responder {
+    jobs.get(&path.id).map(web::Json).ok_or(Error::UnknownJob)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UnknownJob => write!(f, "unknown job"),
+        }
+    }
+}
+
+impl ResponseError for Error {
+    fn status_code(&self) -> http::StatusCode {
+        match self {
+            Error::UnknownJob => http::StatusCode::NOT_FOUND,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        error::respond(self)
+    }
+}
+
+impl ApiError for Error {
+    fn all() -> Vec<Self> {
+        vec![Error::UnknownJob]
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            Error::UnknownJob => "unknown_job",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lifecycle() {
+        let jobs = Jobs::default();
+        let id = jobs.insert();
+        assert!(matches!(jobs.get(&id), Some(Status::Queued)));
+        assert_ne!(jobs.insert(), id);
+
+        jobs.update(&id, Status::Generating);
+        assert!(matches!(jobs.get(&id), Some(Status::Generating)));
+        jobs.update(
+            &id,
+            Status::Done {
+                location: "test".into(),
+            },
+        );
+        assert!(matches!(
+            jobs.get(&id),
+            Some(Status::Done { location }) if location == "test"
+        ));
+
+        jobs.remove(&id);
+        assert!(jobs.get(&id).is_none());
+        assert!(jobs.get("unknown").is_none());
+    }
+}
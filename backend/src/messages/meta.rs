@@ -0,0 +1,167 @@
+use std::fmt;
+use std::sync;
+use std::time;
+
+use actix_http::error::ResponseError;
+use actix_web::{get, http, web, HttpResponse, Responder};
+use schemars::JsonSchema;
+
+use super::error::{self, ApiError};
+use super::name;
+use super::openapi;
+use crate::store;
+
+/// The parameters passed in the path.
+#[derive(Deserialize)]
+pub struct Path {
+    /// The name of the message.
+    message_name: String,
+}
+
+/// Public information about a message.
+#[derive(Debug, JsonSchema, Serialize)]
+#[schemars(rename = "Meta")]
+pub struct Response {
+    /// The type of maze.
+    #[schemars(with = "openapi::Shape")]
+    shape: maze::Shape,
+
+    /// The number of rooms.
+    rooms: usize,
+
+    /// The area covered by the maze.
+    viewbox: super::Viewbox,
+
+    /// The time at which the message was created, in milliseconds since the
+    /// epoch.
+    created: u64,
+
+    /// The time remaining until the message expires, in milliseconds.
+    ttl: u64,
+}
+
+/// The possible error values.
+#[derive(Debug)]
+pub enum Error {
+    /// The message is unknown.
+    UnknownMessage,
+
+    /// The message name is invalid.
+    NameInvalid(name::Error),
+
+    /// The message was stored without a summary.
+    Unavailable,
+
+    /// The store cannot be reached.
+    StoreUnavailable,
+
+    /// An internal error occurred.
+    InternalError,
+}
+
+/// Reads public information about a message.
+///
+/// The response does not reveal anything about the text of the message.
+#[get("/{message_name}/meta")]
+pub async fn handle(
+    path: web::Path<Path>,
+    store: web::Data<sync::Arc<dyn store::Store>>,
+) -> impl Responder {
+    let name = path.message_name.parse::<name::Name>()?;
+
+    let store = store.get_ref().clone();
+    let response = super::block(move || -> Result<_, Error> {
+        let ttl = store
+            .expires_in(name.as_str())?
+            .ok_or(Error::UnknownMessage)?;
+        let (summary, created) = store
+            .get_summary(name.as_str())?
+            .ok_or(Error::Unavailable)?;
+
+        Ok(Response {
+            shape: summary.shape,
+            rooms: summary.rooms,
+            viewbox: summary.viewbox,
+            created: created
+                .duration_since(time::UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            ttl: ttl.as_millis() as u64,
+        })
+    })
+    .await?;
+
+    Ok::<_, Error>(web::Json(response))
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UnknownMessage => write!(f, "unknown message"),
+            Error::NameInvalid(e) => write!(f, "name invalid: {}", e),
+            Error::Unavailable => write!(f, "metadata unavailable"),
+            Error::StoreUnavailable => write!(f, "store unavailable"),
+            Error::InternalError => write!(f, "internal error"),
+        }
+    }
+}
+
+impl ResponseError for Error {
+    fn status_code(&self) -> http::StatusCode {
+        match self {
+            Error::UnknownMessage => http::StatusCode::NOT_FOUND,
+            Error::NameInvalid(_) => http::StatusCode::BAD_REQUEST,
+            Error::Unavailable => http::StatusCode::NOT_FOUND,
+            Error::StoreUnavailable => http::StatusCode::SERVICE_UNAVAILABLE,
+            Error::InternalError => http::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        error::respond(self)
+    }
+}
+
+impl ApiError for Error {
+    fn all() -> Vec<Self> {
+        vec![
+            Error::UnknownMessage,
+            Error::NameInvalid(name::Error::TooShort),
+            Error::Unavailable,
+            Error::StoreUnavailable,
+            Error::InternalError,
+        ]
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            Error::UnknownMessage => "unknown_message",
+            Error::NameInvalid(_) => "name_invalid",
+            Error::Unavailable => "metadata_unavailable",
+            Error::StoreUnavailable => "store_unavailable",
+            Error::InternalError => "internal_error",
+        }
+    }
+
+    fn details(&self) -> Option<serde_json::Value> {
+        match self {
+            Error::NameInvalid(e) => error::name_details(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<name::Error> for Error {
+    fn from(source: name::Error) -> Self {
+        Self::NameInvalid(source)
+    }
+}
+
+impl From<store::Error> for Error {
+    fn from(source: store::Error) -> Self {
+        match source {
+            store::Error::Connection => Self::StoreUnavailable,
+            _ => Self::InternalError,
+        }
+    }
+}
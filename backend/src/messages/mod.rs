@@ -1,6 +1,14 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::ops;
+use std::time;
 
+use actix_http::error::BlockingError;
 use actix_session::Session;
+use actix_web::{http, web, HttpRequest};
+use rand::Rng;
+use schemars::JsonSchema;
+use sha2::{Digest, Sha256};
 
 use maze::initialize;
 use maze::matrix;
@@ -9,13 +17,34 @@ use maze_tools::alphabet;
 use maze_tools::cell::*;
 use maze_tools::image::Color;
 
+use crate::store;
+
 pub mod create;
+pub mod delete;
+pub mod edit;
+pub mod error;
+pub mod generator;
+pub mod jobs;
+pub mod meta;
+pub mod name;
+pub mod openapi;
 pub mod read;
+pub mod resume;
 pub mod update;
 pub mod xid;
 
 /// The name of the room identifier cookie.
-const XID_COOKIE: &'static str = "xid";
+const XID_COOKIE: &'static str = "xids";
+
+/// The name of the cookie holding the value from which the session key is
+/// derived.
+const NONCE_COOKIE: &'static str = "nonce";
+
+/// The maximum number of messages for which the current room is remembered.
+const MAX_POSITIONS: usize = 8;
+
+/// The number of random bytes in an exploration token.
+const TOKEN_LENGTH: usize = 16;
 
 /// The colour of the text.
 const TEXT_COLOR: Color = Color {
@@ -25,6 +54,40 @@ const TEXT_COLOR: Color = Color {
     alpha: 255,
 };
 
+/// Settings for exploring messages.
+#[derive(Clone, Debug)]
+pub struct Navigation {
+    /// The maximum age of an identifier cookie.
+    pub max_age: time::Duration,
+
+    /// Whether explorations are persisted so that they can be resumed.
+    pub resume: bool,
+
+    /// The maximum lifetime of a message, if reading a message extends its
+    /// lifetime.
+    pub max_lifetime: Option<time::Duration>,
+}
+
+/// Bounds for the lifetime of messages requested by clients.
+#[derive(Clone, Debug)]
+pub struct Lifetime {
+    /// The minimum time-to-live of a message.
+    pub min_ttl: time::Duration,
+
+    /// The maximum time-to-live of a message.
+    pub max_ttl: time::Duration,
+}
+
+impl Lifetime {
+    /// Clamps a requested time-to-live to the bounds.
+    ///
+    /// # Arguments
+    /// *  `ttl` - The requested time-to-live.
+    pub fn clamp(&self, ttl: time::Duration) -> time::Duration {
+        ttl.max(self.min_ttl).min(self.max_ttl)
+    }
+}
+
 /// Information for a single room.
 #[derive(Clone, Copy, Default)]
 pub struct Cell {
@@ -38,13 +101,67 @@ pub struct Cell {
 /// The maze type.
 pub type Maze = maze::Maze<Cell>;
 
+/// The parameters from which a message is generated.
+///
+/// Generation is deterministic, so a message can be regenerated from its
+/// recipe at any time.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Recipe {
+    /// The name of the message.
+    pub name: String,
+
+    /// The actual text.
+    pub text: String,
+
+    /// The type of maze to generate.
+    pub shape: maze::Shape,
+
+    /// The random seed.
+    pub seed: initialize::LFSR,
+}
+
+/// The area covered by a maze.
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema, Serialize)]
+pub struct Viewbox {
+    /// The horizontal position of the top left corner.
+    pub x: f32,
+
+    /// The vertical position of the top left corner.
+    pub y: f32,
+
+    /// The width.
+    pub width: f32,
+
+    /// The height.
+    pub height: f32,
+}
+
+/// Public information about a message, not revealing its text.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Summary {
+    /// The type of maze.
+    pub shape: maze::Shape,
+
+    /// The number of rooms.
+    pub rooms: usize,
+
+    /// The area covered by the maze.
+    pub viewbox: Viewbox,
+}
+
 /// A cached message.
 pub struct Message {
-    /// The name of this message.
-    name: String,
+    /// The recipe of this message.
+    recipe: Recipe,
 
     /// The actual maze.
     maze: Maze,
+
+    /// The positions of all rooms, keyed on identifier.
+    positions: HashMap<xid::Identifier, matrix::Pos>,
+
+    /// The area covered by the maze.
+    viewbox: Viewbox,
 }
 
 impl Message {
@@ -55,17 +172,25 @@ impl Message {
     /// *  `text` - The actual text.
     /// *  `shape` - The type of maze to generate.
     /// *  `seed` - The random seed.
+    /// *  `key` - The key from which room identifiers are derived.
     pub fn new(
         name: &str,
         text: &str,
         shape: maze::Shape,
         mut seed: initialize::LFSR,
+        key: &xid::Key,
     ) -> Self {
         // Let the matrix be square-ish
-        let columns = (text.len() as f32).sqrt().ceil() as usize;
-        let rows = (text.len() as f32 / columns as f32).ceil() as usize;
+        let length = text.chars().count();
+        let columns = (length as f32).sqrt().ceil() as usize;
+        let rows = (length as f32 / columns as f32).ceil() as usize;
 
-        let name = name.to_owned();
+        let recipe = Recipe {
+            name: name.to_owned(),
+            text: text.to_owned(),
+            shape,
+            seed,
+        };
         let (width, height) =
             shape.minimal_dimensions(columns as f32 * 16.0, rows as f32 * 16.0);
         let viewbox = shape.viewbox(width, height);
@@ -83,18 +208,68 @@ impl Message {
             .split_by(&shape, width, height)
             .map(|&color| Cell {
                 color,
-                id: seed.advance().into(),
+                id: key.identifier(seed.advance()),
             });
         let maze = shape
             .create_with_data(data.width, data.height, |pos| data[pos])
             .initialize(initialize::Method::Branching, &mut seed);
+        let positions = maze
+            .positions()
+            .filter_map(|pos| maze.data(pos).map(|data| (data.id, pos)))
+            .collect();
+
+        Self {
+            recipe,
+            maze,
+            positions,
+            viewbox: Viewbox {
+                x: viewbox.corner.x,
+                y: viewbox.corner.y,
+                width: viewbox.width,
+                height: viewbox.height,
+            },
+        }
+    }
 
-        Self { name, maze }
+    /// Regenerates a message from its recipe.
+    ///
+    /// # Arguments
+    /// *  `recipe` - The recipe of the message.
+    /// *  `key` - The key from which room identifiers are derived.
+    pub fn from_recipe(recipe: &Recipe, key: &xid::Key) -> Self {
+        Self::new(&recipe.name, &recipe.text, recipe.shape, recipe.seed, key)
     }
 
     /// The name of this message.
     pub fn name(&self) -> &str {
-        &self.name
+        &self.recipe.name
+    }
+
+    /// The recipe of this message.
+    pub fn recipe(&self) -> &Recipe {
+        &self.recipe
+    }
+
+    /// Public information about this message.
+    pub fn summary(&self) -> Summary {
+        Summary {
+            shape: self.recipe.shape,
+            rooms: self.positions.len(),
+            viewbox: self.viewbox,
+        }
+    }
+
+    /// Generates a description of a room given its identifier.
+    ///
+    /// # Arguments
+    /// *  `id` - The room ID, or `None` for the entrance.
+    pub fn room(&self, id: Option<xid::Identifier>) -> Option<Room> {
+        match id {
+            Some(id) => {
+                self.positions.get(&id).and_then(|&pos| self.describe(pos))
+            }
+            None => self.describe((0isize, 0isize).into()),
+        }
     }
 
     /// Ierates over all room descriptions.
@@ -129,6 +304,46 @@ impl Message {
     }
 }
 
+/// Registers the message handlers.
+///
+/// The handlers are registered both under the versioned API scope and at the
+/// root, where they remain for clients predating the versioned API.
+///
+/// # Arguments
+/// *  `config` - The configuration to which to add the handlers.
+pub fn configure(config: &mut web::ServiceConfig) {
+    config
+        .service(create::handle)
+        .service(jobs::handle)
+        .service(read::handle)
+        .service(meta::handle)
+        .service(update::handle)
+        .service(edit::handle)
+        .service(delete::handle)
+        .service(resume::token)
+        .service(resume::handle);
+}
+
+/// Finds the characters of a text that the alphabet cannot render.
+///
+/// Whitespace is always supported; any other character is supported if
+/// rendering it on its own yields something other than a blank. Every
+/// unsupported character is listed once, in order of appearance.
+///
+/// # Arguments
+/// *  `text` - The text to check.
+pub fn unsupported_characters(text: &str) -> Vec<char> {
+    let mut seen = HashSet::new();
+    text.chars()
+        .filter(|&c| !c.is_whitespace() && seen.insert(c))
+        .filter(|&c| {
+            !alphabet::default::ALPHABET
+                .render(&c.to_string(), 1, 16)
+                .any(|(_, v)| v > 0.0)
+        })
+        .collect()
+}
+
 /// An intermediate value use to accumulate data for a room.
 #[derive(Clone, Copy, Default)]
 struct Intermediate(physical::Pos, f32);
@@ -168,12 +383,13 @@ impl ops::Div<usize> for Intermediate {
 }
 
 /// A room description.
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
 pub struct Room {
     /// The room identifier.
     pub xid: xid::Identifier,
 
     /// The position of the corners of the room.
+    #[schemars(with = "Vec<openapi::Pos>")]
     pub pos: Vec<physical::Pos>,
 
     /// The colour of the room.
@@ -183,38 +399,383 @@ pub struct Room {
     pub see: Vec<xid::Identifier>,
 }
 
-/// Clears the session cookie.
+impl Room {
+    /// Encrypts all identifiers of this room.
+    ///
+    /// # Arguments
+    /// *  `key` - The key with which to encrypt.
+    pub fn encrypt(mut self, key: &xid::Key) -> Self {
+        self.xid = key.encrypt(self.xid);
+        self.see = self.see.into_iter().map(|id| key.encrypt(id)).collect();
+        self
+    }
+}
+
+/// A persisted exploration of a message.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Exploration {
+    /// The name of the explored message.
+    pub message: String,
+
+    /// The current room.
+    pub current: xid::Identifier,
+
+    /// The number of moves made.
+    pub sequence: u64,
+}
+
+/// The state of the exploration of a single message in a session.
+#[derive(Deserialize, Serialize)]
+struct Position {
+    /// The name of the message.
+    name: String,
+
+    /// The identifier cookie value of the current room.
+    xid: Option<String>,
+
+    /// The token under which the exploration is persisted.
+    token: Option<String>,
+
+    /// The number of moves made in the persisted exploration when the
+    /// current room was entered.
+    #[serde(default)]
+    sequence: Option<u64>,
+}
+
+/// The positions in all explored messages, least recently used first.
+type Positions = Vec<Position>;
+
+/// Loads the positions in all explored messages from the session.
 ///
 /// # Arguments
 /// *  `session` - The session.
-pub fn clear_id(session: &Session) {
-    session.remove(XID_COOKIE);
+fn load_positions(session: &Session) -> Result<Positions, xid::Error> {
+    session
+        .get::<Positions>(XID_COOKIE)
+        .map_err(|_| xid::Error::Format)
+        .map(Option::unwrap_or_default)
 }
 
-/// Loads the identifier cookie from the session.
+/// Stores the positions in all explored messages to the session.
 ///
 /// # Arguments
 /// *  `session` - The session.
-pub fn load_id(session: &Session) -> Result<xid::Identifier, xid::Error> {
-    session
-        .get::<String>(XID_COOKIE)
-        .map_err(|_| xid::Error::Format)
-        .and_then(|c| c.ok_or(xid::Error::Missing))
-        .and_then(|s| s.parse::<xid::IdentifierCookie>())
-        .map(xid::Identifier::from)
+/// *  `positions` - The positions to store.
+fn store_positions(
+    session: &Session,
+    positions: Positions,
+) -> Result<(), xid::Error> {
+    if positions.is_empty() {
+        session.remove(XID_COOKIE);
+        Ok(())
+    } else {
+        session
+            .set(XID_COOKIE, positions)
+            .map_err(|_| xid::Error::Format)
+    }
+}
+
+/// Updates the position in a message.
+///
+/// The message becomes the most recently used one, and only the
+/// [`MAX_POSITIONS`] most recently used messages are remembered.
+///
+/// # Arguments
+/// *  `session` - The session.
+/// *  `message_name` - The name of the message.
+/// *  `update` - A function updating the position.
+fn update_position<F>(
+    session: &Session,
+    message_name: &str,
+    update: F,
+) -> Result<(), xid::Error>
+where
+    F: FnOnce(&mut Position),
+{
+    let mut positions = load_positions(session).unwrap_or_default();
+    let mut position = positions
+        .iter()
+        .position(|position| position.name == message_name)
+        .map(|i| positions.remove(i))
+        .unwrap_or_else(|| Position {
+            name: message_name.into(),
+            xid: None,
+            token: None,
+            sequence: None,
+        });
+    update(&mut position);
+    positions.push(position);
+    if positions.len() > MAX_POSITIONS {
+        positions.drain(..positions.len() - MAX_POSITIONS);
+    }
+    store_positions(session, positions)
+}
+
+/// Clears the position in a message from the session cookie.
+///
+/// # Arguments
+/// *  `session` - The session.
+/// *  `message_name` - The name of the message.
+pub fn clear_id(session: &Session, message_name: &str) {
+    if let Ok(mut positions) = load_positions(session) {
+        positions.retain(|position| position.name != message_name);
+        let _ = store_positions(session, positions);
+    } else {
+        session.remove(XID_COOKIE);
+    }
+}
+
+/// Loads the identifier cookie for a message from the session.
+///
+/// # Arguments
+/// *  `session` - The session.
+/// *  `message_name` - The name of the message.
+/// *  `max_age` - The maximum age of the cookie.
+pub fn load_cookie(
+    session: &Session,
+    message_name: &str,
+    max_age: time::Duration,
+) -> Result<xid::IdentifierCookie, xid::Error> {
+    load_positions(session)?
+        .into_iter()
+        .find(|position| position.name == message_name)
+        .and_then(|position| position.xid)
+        .ok_or(xid::Error::Missing)
+        .and_then(|s| xid::IdentifierCookie::parse(&s, max_age))
 }
 
-/// Stores an identifier cookie to the session.
+/// Loads the identifier of the current room of a message from the session.
 ///
 /// # Arguments
 /// *  `session` - The session.
+/// *  `message_name` - The name of the message.
+/// *  `max_age` - The maximum age of the cookie.
+pub fn load_id(
+    session: &Session,
+    message_name: &str,
+    max_age: time::Duration,
+) -> Result<xid::Identifier, xid::Error> {
+    load_cookie(session, message_name, max_age).map(xid::Identifier::from)
+}
+
+/// Stores an identifier cookie for a message to the session.
+///
+/// # Arguments
+/// *  `session` - The session.
+/// *  `message_name` - The name of the message.
 /// *  `id` - The identifier to store.
+/// *  `nonce` - The nonce issued for the cookie with [`issue_nonce`].
 pub fn store_id(
     session: &Session,
+    message_name: &str,
     id: xid::Identifier,
+    nonce: String,
 ) -> Result<xid::Identifier, xid::Error> {
-    session
-        .set(XID_COOKIE, xid::IdentifierCookie::from(id).to_string())
-        .map_err(|_| xid::Error::Format)
-        .map(|_| id)
+    update_position(session, message_name, |position| {
+        position.xid = Some(xid::IdentifierCookie::new(id, nonce).to_string())
+    })
+    .map(|_| id)
+}
+
+/// Records a new nonce for an identifier cookie.
+///
+/// The nonce can be used once before the cookie expires.
+///
+/// # Arguments
+/// *  `store` - The store in which to record the nonce.
+/// *  `max_age` - The maximum age of the cookie.
+pub fn issue_nonce(
+    store: &dyn store::Store,
+    max_age: time::Duration,
+) -> Result<String, store::Error> {
+    let nonce = generate_token();
+    store.put_nonce(&nonce, max_age)?;
+    Ok(nonce)
+}
+
+/// Loads the exploration token for a message from the session.
+///
+/// # Arguments
+/// *  `session` - The session.
+/// *  `message_name` - The name of the message.
+pub fn load_token(session: &Session, message_name: &str) -> Option<String> {
+    load_positions(session)
+        .ok()?
+        .into_iter()
+        .find(|position| position.name == message_name)
+        .and_then(|position| position.token)
+}
+
+/// Stores an exploration token for a message to the session.
+///
+/// # Arguments
+/// *  `session` - The session.
+/// *  `message_name` - The name of the message.
+/// *  `token` - The token to store.
+pub fn store_token(
+    session: &Session,
+    message_name: &str,
+    token: String,
+) -> Result<(), xid::Error> {
+    update_position(session, message_name, |position| {
+        position.token = Some(token)
+    })
+}
+
+/// Loads the number of moves made in the persisted exploration of a message
+/// from the session.
+///
+/// # Arguments
+/// *  `session` - The session.
+/// *  `message_name` - The name of the message.
+pub fn load_sequence(session: &Session, message_name: &str) -> Option<u64> {
+    load_positions(session)
+        .ok()?
+        .into_iter()
+        .find(|position| position.name == message_name)
+        .and_then(|position| position.sequence)
+}
+
+/// Stores the number of moves made in the persisted exploration of a message
+/// to the session.
+///
+/// # Arguments
+/// *  `session` - The session.
+/// *  `message_name` - The name of the message.
+/// *  `sequence` - The number of moves.
+pub fn store_sequence(
+    session: &Session,
+    message_name: &str,
+    sequence: u64,
+) -> Result<(), xid::Error> {
+    update_position(session, message_name, |position| {
+        position.sequence = Some(sequence)
+    })
+}
+
+/// Derives the key used to encrypt room identifiers in a session.
+///
+/// A random value is stored in the session the first time this is called.
+/// The key also depends on the server secret, so the value does not reveal
+/// it.
+///
+/// # Arguments
+/// *  `session` - The session.
+/// *  `key` - The server key.
+pub fn session_key(
+    session: &Session,
+    key: &xid::Key,
+) -> Result<xid::Key, xid::Error> {
+    let nonce = match session
+        .get::<String>(NONCE_COOKIE)
+        .map_err(|_| xid::Error::Format)?
+    {
+        Some(nonce) => nonce,
+        None => {
+            let nonce = generate_token();
+            session
+                .set(NONCE_COOKIE, &nonce)
+                .map_err(|_| xid::Error::Format)?;
+            nonce
+        }
+    };
+    Ok(key.derive(format!("session:{}", nonce).as_bytes()))
+}
+
+/// Looks up the persisted exploration of a message.
+///
+/// This is used when the identifier cookie has expired or is missing, and to
+/// check that no other move has been made since the current room was
+/// entered.
+///
+/// # Arguments
+/// *  `store` - The store holding the exploration.
+/// *  `message_name` - The name of the message.
+/// *  `token` - The exploration token.
+pub fn resume(
+    store: &dyn store::Store,
+    message_name: &str,
+    token: &str,
+) -> Result<Option<Exploration>, store::Error> {
+    Ok(store
+        .get_exploration(token)?
+        .filter(|exploration| exploration.message == message_name))
+}
+
+/// Extends the lifetime of a message that has been read.
+///
+/// # Arguments
+/// *  `store` - The store holding the message.
+/// *  `message_name` - The name of the message.
+/// *  `max_lifetime` - The maximum lifetime of the message, or `None` if
+///    reading a message does not extend its lifetime.
+pub fn touch(
+    store: &dyn store::Store,
+    message_name: &str,
+    max_lifetime: Option<time::Duration>,
+) -> Result<(), store::Error> {
+    match max_lifetime {
+        Some(max_lifetime) => store.touch(message_name, max_lifetime),
+        None => Ok(()),
+    }
+}
+
+/// Runs a function accessing the store on the blocking thread pool.
+///
+/// # Arguments
+/// *  `f` - The function to run.
+pub async fn block<F, T, E>(f: F) -> Result<T, E>
+where
+    F: FnOnce() -> Result<T, E> + Send + 'static,
+    T: Send + 'static,
+    E: From<store::Error> + fmt::Debug + Send + 'static,
+{
+    web::block(f).await.map_err(|e| match e {
+        BlockingError::Error(e) => e,
+        BlockingError::Canceled => store::Error::InternalError.into(),
+    })
+}
+
+/// Generates a random token.
+pub fn generate_token() -> String {
+    let mut rng = rand::thread_rng();
+    (0..TOKEN_LENGTH)
+        .map(|_| format!("{:02x}", rng.gen::<u8>()))
+        .collect()
+}
+
+/// Generates a random seed for a maze.
+pub fn generate_seed() -> initialize::LFSR {
+    // The all-zero state of an LFSR never changes
+    initialize::LFSR::new(rand::thread_rng().gen_range(1..=u64::MAX))
+}
+
+/// Hashes a management token for storage.
+///
+/// # Arguments
+/// *  `token` - The token to hash.
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Extracts the bearer token from the `Authorization` header of a request.
+///
+/// # Arguments
+/// *  `request` - The request.
+pub fn bearer_token(request: &HttpRequest) -> Option<String> {
+    let value = request
+        .headers()
+        .get(http::header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .trim();
+    let (scheme, token) = value.split_at(value.find(' ')?);
+    if scheme.eq_ignore_ascii_case("bearer") {
+        Some(token.trim().into())
+    } else {
+        None
+    }
 }
@@ -0,0 +1,148 @@
+use std::fmt;
+use std::str;
+
+/// The minimum length of a name.
+pub const MIN_LENGTH: usize = 1;
+
+/// The maximum length of a name.
+pub const MAX_LENGTH: usize = 64;
+
+/// The names that cannot be given to new messages, since they are the first
+/// path segment of other routes.
+pub const RESERVED: &[&str] = &["jobs"];
+
+/// A name parse error.
+#[derive(Debug)]
+pub enum Error {
+    /// The name is shorter than [`MIN_LENGTH`].
+    TooShort,
+
+    /// The name is longer than [`MAX_LENGTH`].
+    TooLong,
+
+    /// The name contains a character that is not allowed.
+    InvalidCharacter(char),
+
+    /// The name is in [`RESERVED`].
+    Reserved,
+}
+
+/// A message name.
+///
+/// Names are case insensitive and consist of ASCII letters, digits, `-` and
+/// `_`. Parsing a name folds it to lower case, so two names that differ only
+/// by case refer to the same message.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Name(String);
+
+impl Name {
+    /// The normalised name.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Whether this name is reserved, and cannot be given to a new message.
+    pub fn is_reserved(&self) -> bool {
+        RESERVED.contains(&self.as_str())
+    }
+}
+
+impl str::FromStr for Name {
+    type Err = Error;
+
+    /// Parses and normalises a message name.
+    ///
+    /// # Arguments
+    /// *  `source` - The string to parse.
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        if let Some(c) = source
+            .chars()
+            .find(|&c| !(c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+        {
+            Err(Error::InvalidCharacter(c))
+        } else if source.len() < MIN_LENGTH {
+            Err(Error::TooShort)
+        } else if source.len() > MAX_LENGTH {
+            Err(Error::TooLong)
+        } else {
+            Ok(Name(source.to_ascii_lowercase()))
+        }
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<Name> for String {
+    fn from(source: Name) -> Self {
+        source.0
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::TooShort => {
+                write!(f, "name must be at least {} characters", MIN_LENGTH)
+            }
+            Error::TooLong => {
+                write!(f, "name must be at most {} characters", MAX_LENGTH)
+            }
+            Error::InvalidCharacter(c) => write!(
+                f,
+                "name contains {:?}; only letters, digits, '-' and '_' are \
+                 allowed",
+                c,
+            ),
+            Error::Reserved => write!(f, "name is reserved"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_folds_case() {
+        let name = "Hello-World_1".parse::<Name>().unwrap();
+        assert_eq!(name.as_str(), "hello-world_1");
+        assert_eq!(name, "HELLO-world_1".parse::<Name>().unwrap());
+    }
+
+    #[test]
+    fn parse_checks_length() {
+        assert!(matches!("".parse::<Name>(), Err(Error::TooShort)));
+        assert!("a".repeat(MAX_LENGTH).parse::<Name>().is_ok());
+        assert!(matches!(
+            "a".repeat(MAX_LENGTH + 1).parse::<Name>(),
+            Err(Error::TooLong)
+        ));
+    }
+
+    #[test]
+    fn reserved_names() {
+        assert!("jobs".parse::<Name>().unwrap().is_reserved());
+        assert!("JOBS".parse::<Name>().unwrap().is_reserved());
+        assert!(!"jobs-1".parse::<Name>().unwrap().is_reserved());
+    }
+
+    #[test]
+    fn parse_checks_characters() {
+        assert!(matches!(
+            "hello world".parse::<Name>(),
+            Err(Error::InvalidCharacter(' '))
+        ));
+        assert!(matches!(
+            "café".parse::<Name>(),
+            Err(Error::InvalidCharacter('é'))
+        ));
+        assert!(matches!(
+            "a/b".parse::<Name>(),
+            Err(Error::InvalidCharacter('/'))
+        ));
+    }
+}
@@ -0,0 +1,414 @@
+use std::collections::BTreeMap;
+
+use actix_http::error::ResponseError;
+use actix_web::{get, http, web, Responder};
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::{InstanceType, Schema, SchemaObject};
+use schemars::JsonSchema;
+use serde_json::json;
+
+use super::error::{ApiError, RequestInvalid};
+use super::{create, delete, edit, error, jobs, meta, read, resume, update};
+
+/// The version of the API described by the document.
+pub const VERSION: &str = "1";
+
+/// The schema of a maze shape.
+///
+/// The values are the serialised forms of the shapes, so they always match
+/// what the handlers accept.
+pub struct Shape;
+
+impl Shape {
+    /// All maze shapes.
+    const ALL: &'static [maze::Shape] =
+        &[maze::Shape::Hex, maze::Shape::Quad, maze::Shape::Tri];
+}
+
+impl JsonSchema for Shape {
+    fn schema_name() -> String {
+        "Shape".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        SchemaObject {
+            instance_type: Some(InstanceType::String.into()),
+            enum_values: Some(
+                Self::ALL
+                    .iter()
+                    .filter_map(|shape| serde_json::to_value(shape).ok())
+                    .collect(),
+            ),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+/// The schema of a random seed.
+pub struct Seed;
+
+impl JsonSchema for Seed {
+    fn schema_name() -> String {
+        "Seed".into()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        gen.subschema_for::<u64>()
+    }
+}
+
+/// The schema of a physical position.
+#[derive(JsonSchema)]
+pub struct Pos {
+    /// The horizontal position.
+    pub x: f32,
+
+    /// The vertical position.
+    pub y: f32,
+}
+
+/// The kinds of errors with which a handler may respond, as status and code.
+fn errors<E>() -> Vec<(http::StatusCode, &'static str)>
+where
+    E: ApiError,
+{
+    E::all()
+        .iter()
+        .map(|e| (ResponseError::status_code(e), e.code()))
+        .collect()
+}
+
+/// The kinds of errors with which a handler reading a JSON body may respond.
+fn body_errors<E>() -> Vec<(http::StatusCode, &'static str)>
+where
+    E: ApiError,
+{
+    [errors::<E>(), errors::<RequestInvalid>()].concat()
+}
+
+/// Describes the API as an OpenAPI 3 document.
+#[get("/openapi.json")]
+pub async fn handle() -> impl Responder {
+    web::Json(document())
+}
+
+/// Generates an OpenAPI 3 document describing the API.
+///
+/// The schemas of all request and response bodies are generated from the
+/// types used by the handlers, and the error responses from their error
+/// types.
+pub fn document() -> serde_json::Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let create_request = gen.subschema_for::<create::Request>();
+    let created = gen.subschema_for::<create::Body>();
+    let status = gen.subschema_for::<jobs::Status>();
+    let room = gen.subschema_for::<super::Room>();
+    let meta = gen.subschema_for::<meta::Response>();
+    let update_request = gen.subschema_for::<update::Request>();
+    let edit_request = gen.subschema_for::<edit::Request>();
+    let token = gen.subschema_for::<resume::Token>();
+    let resume_request = gen.subschema_for::<resume::Request>();
+    let exploration = gen.subschema_for::<resume::Response>();
+    let error = gen.subschema_for::<error::Body>();
+
+    let name = json!({
+        "name": "message_name",
+        "in": "path",
+        "required": true,
+        "schema": {"type": "string"},
+    });
+    let responses =
+        |mut responses: serde_json::Value,
+         kinds: Vec<(http::StatusCode, &'static str)>| {
+            // Every status is documented once, with all codes sharing it
+            let mut codes = BTreeMap::<u16, Vec<&str>>::new();
+            for (status, code) in kinds {
+                let codes = codes.entry(status.as_u16()).or_default();
+                if !codes.contains(&code) {
+                    codes.push(code);
+                }
+            }
+            for (status, codes) in codes {
+                let codes = codes
+                    .iter()
+                    .map(|code| format!("`{}`", code))
+                    .collect::<Vec<_>>();
+                responses[status.to_string()] = json!({
+                    "description":
+                        format!("Error codes: {}.", codes.join(", ")),
+                    "content": {"application/json": {"schema": error}},
+                });
+            }
+            responses
+        };
+    let success = |description: &str, schema: &Schema| {
+        json!({
+            "description": description,
+            "content": {"application/json": {"schema": schema}},
+        })
+    };
+    let body = |schema: &Schema| {
+        json!({
+            "required": true,
+            "content": {"application/json": {"schema": schema}},
+        })
+    };
+    let management = json!([{"token": []}]);
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Brizzo",
+            "version": VERSION,
+        },
+        "servers": [{"url": format!("/api/v{}", VERSION)}],
+        "paths": {
+            "/": {
+                "post": {
+                    "operationId": "create",
+                    "summary": "Creates a message.",
+                    "parameters": [{
+                        "name": "Prefer",
+                        "in": "header",
+                        "schema": {"type": "string", "enum": ["respond-async"]},
+                    }],
+                    "requestBody": body(&create_request),
+                    "responses": responses(
+                        json!({
+                            "201":
+                                success("The message was created.", &created),
+                            "202":
+                                success("A creation job was queued.", &created),
+                        }),
+                        body_errors::<create::Error>(),
+                    ),
+                },
+            },
+            "/jobs/{id}": {
+                "get": {
+                    "operationId": "job",
+                    "summary": "Reads the status of a creation job.",
+                    "parameters": [{
+                        "name": "id",
+                        "in": "path",
+                        "required": true,
+                        "schema": {"type": "string"},
+                    }],
+                    "responses": responses(
+                        json!({
+                            "200": success("The job status.", &status),
+                        }),
+                        errors::<jobs::Error>(),
+                    ),
+                },
+            },
+            "/{message_name}": {
+                "parameters": [name],
+                "get": {
+                    "operationId": "read",
+                    "summary": "Reads the current room.",
+                    "responses": responses(
+                        json!({
+                            "200": success("The current room.", &room),
+                        }),
+                        errors::<read::Error>(),
+                    ),
+                },
+                "put": {
+                    "operationId": "move",
+                    "summary": "Moves to a neighbouring room.",
+                    "requestBody": body(&update_request),
+                    "responses": responses(
+                        json!({
+                            "200": success("The new room.", &room),
+                        }),
+                        body_errors::<update::Error>(),
+                    ),
+                },
+                "patch": {
+                    "operationId": "edit",
+                    "summary": "Regenerates a message.",
+                    "security": management,
+                    "requestBody": body(&edit_request),
+                    "responses": responses(
+                        json!({
+                            "204": {
+                                "description": "The message was regenerated.",
+                            },
+                        }),
+                        body_errors::<edit::Error>(),
+                    ),
+                },
+                "delete": {
+                    "operationId": "delete",
+                    "summary": "Removes a message.",
+                    "security": management,
+                    "responses": responses(
+                        json!({
+                            "204": {"description": "The message was removed."},
+                        }),
+                        errors::<delete::Error>(),
+                    ),
+                },
+            },
+            "/{message_name}/meta": {
+                "parameters": [name],
+                "get": {
+                    "operationId": "meta",
+                    "summary": "Reads public information about a message.",
+                    "responses": responses(
+                        json!({
+                            "200": success("The message information.", &meta),
+                        }),
+                        errors::<meta::Error>(),
+                    ),
+                },
+            },
+            "/{message_name}/resume": {
+                "parameters": [name],
+                "get": {
+                    "operationId": "resumeToken",
+                    "summary": "Reads the token of the current exploration.",
+                    "responses": responses(
+                        json!({
+                            "200": success("The exploration token.", &token),
+                        }),
+                        errors::<resume::Error>(),
+                    ),
+                },
+                "post": {
+                    "operationId": "resume",
+                    "summary": "Resumes a persisted exploration.",
+                    "requestBody": body(&resume_request),
+                    "responses": responses(
+                        json!({
+                            "200": success("The exploration.", &exploration),
+                        }),
+                        body_errors::<resume::Error>(),
+                    ),
+                },
+            },
+        },
+        "components": {
+            "schemas": gen.definitions(),
+            "securitySchemes": {
+                "token": {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "description": "The token returned when the message was \
+                        created.",
+                },
+            },
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http, test, App};
+
+    use super::*;
+    use crate::messages;
+
+    #[test]
+    fn shapes_are_accepted() {
+        let document = document();
+        let values = document["components"]["schemas"]["Shape"]["enum"]
+            .as_array()
+            .expect("shape values");
+        assert_eq!(values.len(), Shape::ALL.len());
+        for value in values {
+            serde_json::from_value::<maze::Shape>(value.clone())
+                .expect("valid shape");
+        }
+    }
+
+    #[test]
+    fn errors_are_documented() {
+        let document = document();
+        let operations = vec![
+            ("/", "post", body_errors::<create::Error>()),
+            ("/jobs/{id}", "get", errors::<jobs::Error>()),
+            ("/{message_name}", "get", errors::<read::Error>()),
+            ("/{message_name}", "put", body_errors::<update::Error>()),
+            ("/{message_name}", "patch", body_errors::<edit::Error>()),
+            ("/{message_name}", "delete", errors::<delete::Error>()),
+            ("/{message_name}/meta", "get", errors::<meta::Error>()),
+            ("/{message_name}/resume", "get", errors::<resume::Error>()),
+            (
+                "/{message_name}/resume",
+                "post",
+                body_errors::<resume::Error>(),
+            ),
+        ];
+        for (path, method, kinds) in operations {
+            let responses = &document["paths"][path][method]["responses"];
+            for (status, code) in kinds {
+                let description = responses[status.as_str()]["description"]
+                    .as_str()
+                    .unwrap_or_default();
+                assert!(
+                    description.contains(&format!("`{}`", code)),
+                    "{} {} does not document {} {}",
+                    method,
+                    path,
+                    status,
+                    code,
+                );
+            }
+        }
+
+        // Handlers reading a path always validate the message name
+        assert!(document["paths"]["/{message_name}/resume"]["get"]
+            ["responses"]["400"]
+            .is_object());
+    }
+
+    #[actix_rt::test]
+    async fn documented_routes_exist() {
+        let mut app = test::init_service(
+            App::new().app_data(error::json_config()).service(
+                web::scope(&format!("/api/v{}", VERSION))
+                    .service(handle)
+                    .configure(messages::configure),
+            ),
+        )
+        .await;
+
+        let document = document();
+        let paths = document["paths"].as_object().expect("paths");
+        for (path, item) in paths {
+            let uri = format!("/api/v{}{}", VERSION, path)
+                .replace("{message_name}", "message")
+                .replace("{id}", "job");
+            let methods = item
+                .as_object()
+                .expect("path item")
+                .keys()
+                .filter(|key| *key != "parameters");
+            for method in methods {
+                let req = test::TestRequest::with_uri(&uri)
+                    .method(
+                        http::Method::from_bytes(
+                            method.to_uppercase().as_bytes(),
+                        )
+                        .expect("method"),
+                    )
+                    .to_request();
+                let res = test::call_service(&mut app, req).await;
+
+                // Unrouted requests get an empty 404, while the handlers
+                // always describe their errors
+                let status = res.status();
+                let body = test::read_body(res).await;
+                assert!(
+                    status != http::StatusCode::NOT_FOUND || !body.is_empty(),
+                    "{} {} is not routed",
+                    method,
+                    path,
+                );
+            }
+        }
+    }
+}
@@ -3,9 +3,10 @@ use std::sync;
 
 use actix_http::error::ResponseError;
 use actix_session::Session;
-use actix_web::{get, http, web, Responder};
+use actix_web::{get, http, web, HttpResponse, Responder};
 
-use super::xid;
+use super::error::{self, ApiError};
+use super::{name, xid};
 use crate::store;
 
 /// The parameters passed in the path.
@@ -21,9 +22,18 @@ pub enum Error {
     /// The message is unknown.
     UnknownMessage,
 
+    /// The message name is invalid.
+    NameInvalid(name::Error),
+
     /// The room is unknown.
     UnknownRoom,
 
+    /// The identifier cookie is invalid.
+    CookieInvalid(xid::Error),
+
+    /// The store cannot be reached.
+    StoreUnavailable,
+
     /// An internal error occurred.
     InternalError,
 }
@@ -31,23 +41,92 @@ pub enum Error {
 #[get("/{message_name}")]
 pub async fn handle(
     path: web::Path<Path>,
-    store: web::Data<sync::Arc<sync::Mutex<store::Store>>>,
+    store: web::Data<sync::Arc<dyn store::Store>>,
+    navigation: web::Data<super::Navigation>,
+    key: web::Data<xid::Key>,
     session: Session,
 ) -> impl Responder {
-    let mut store = store.lock()?;
-
-    if !store.exists(&path.message_name)? {
-        Err(Error::UnknownMessage)
-    } else {
-        let current_id = match super::load_id(&session) {
+    let name = path.message_name.parse::<name::Name>()?;
+    let current_id =
+        match super::load_id(&session, name.as_str(), navigation.max_age) {
             Ok(id) => Some(id),
             Err(xid::Error::Expired) | Err(xid::Error::Missing) => None,
             Err(e) => return Err(e.into()),
         };
-        store
-            .get(&path.message_name, current_id)?
-            .ok_or(Error::UnknownRoom)
-            .map(web::Json)
+    let token = if current_id.is_none() && navigation.resume {
+        super::load_token(&session, name.as_str())
+    } else {
+        None
+    };
+
+    let store = store.get_ref().clone();
+    let message_name = name.clone();
+    let max_lifetime = navigation.max_lifetime;
+    let max_age = navigation.max_age;
+    let (room, resumed, reset, private) =
+        super::block(move || -> Result<_, Error> {
+            if !store.exists(message_name.as_str())? {
+                Err(Error::UnknownMessage)
+            } else {
+                let exploration = match token {
+                    Some(token) => super::resume(
+                        store.as_ref(),
+                        message_name.as_str(),
+                        &token,
+                    )?,
+                    None => None,
+                };
+
+                // All positions resumed from the same exploration share its
+                // sequence number, so only one of them can move
+                let resumed = match &exploration {
+                    Some(exploration) => Some((
+                        exploration.current,
+                        super::issue_nonce(store.as_ref(), max_age)?,
+                        exploration.sequence,
+                    )),
+                    None => None,
+                };
+                let id = current_id
+                    .or(exploration.map(|exploration| exploration.current));
+                let (room, reset) =
+                    match store.get(message_name.as_str(), id)? {
+                        Some(room) => (room, false),
+                        // The message may have been regenerated since the
+                        // room was entered, in which case we start over from
+                        // the entrance
+                        None if id.is_some() => (
+                            store
+                                .get(message_name.as_str(), None)?
+                                .ok_or(Error::UnknownRoom)?,
+                            true,
+                        ),
+                        None => return Err(Error::UnknownRoom),
+                    };
+                super::touch(
+                    store.as_ref(),
+                    message_name.as_str(),
+                    max_lifetime,
+                )?;
+                let private = store.is_private(message_name.as_str())?;
+                Ok((room, resumed, reset, private))
+            }
+        })
+        .await?;
+
+    if reset {
+        log::info!("Reset position in message {}", name);
+        super::clear_id(&session, name.as_str());
+    } else if let Some((id, nonce, sequence)) = resumed {
+        super::store_id(&session, name.as_str(), id, nonce)?;
+        super::store_sequence(&session, name.as_str(), sequence)?;
+    }
+    if private {
+        Ok(web::Json(
+            room.encrypt(&super::session_key(&session, &key)?),
+        ))
+    } else {
+        Ok(web::Json(room))
     }
 }
 
@@ -55,7 +134,10 @@ impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Error::UnknownMessage => write!(f, "unknown message"),
+            Error::NameInvalid(e) => write!(f, "name invalid: {}", e),
             Error::UnknownRoom => write!(f, "unknown room"),
+            Error::CookieInvalid(e) => write!(f, "cookie invalid: {}", e),
+            Error::StoreUnavailable => write!(f, "store unavailable"),
             Error::InternalError => write!(f, "internal error"),
         }
     }
@@ -65,26 +147,67 @@ impl ResponseError for Error {
     fn status_code(&self) -> http::StatusCode {
         match self {
             Error::UnknownMessage => http::StatusCode::NOT_FOUND,
+            Error::NameInvalid(_) => http::StatusCode::BAD_REQUEST,
             Error::UnknownRoom => http::StatusCode::NOT_FOUND,
+            Error::CookieInvalid(_) => http::StatusCode::BAD_REQUEST,
+            Error::StoreUnavailable => http::StatusCode::SERVICE_UNAVAILABLE,
             Error::InternalError => http::StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
+
+    fn error_response(&self) -> HttpResponse {
+        error::respond(self)
+    }
 }
 
-impl From<xid::Error> for Error {
-    fn from(_: xid::Error) -> Self {
-        Self::UnknownRoom
+impl ApiError for Error {
+    fn all() -> Vec<Self> {
+        vec![
+            Error::UnknownMessage,
+            Error::NameInvalid(name::Error::TooShort),
+            Error::UnknownRoom,
+            Error::CookieInvalid(xid::Error::Format),
+            Error::StoreUnavailable,
+            Error::InternalError,
+        ]
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            Error::UnknownMessage => "unknown_message",
+            Error::NameInvalid(_) => "name_invalid",
+            Error::UnknownRoom => "unknown_room",
+            Error::CookieInvalid(_) => "cookie_invalid",
+            Error::StoreUnavailable => "store_unavailable",
+            Error::InternalError => "internal_error",
+        }
+    }
+
+    fn details(&self) -> Option<serde_json::Value> {
+        match self {
+            Error::NameInvalid(e) => error::name_details(e),
+            _ => None,
+        }
     }
 }
 
-impl From<store::Error> for Error {
-    fn from(_source: store::Error) -> Self {
-        Self::InternalError
+impl From<name::Error> for Error {
+    fn from(source: name::Error) -> Self {
+        Self::NameInvalid(source)
     }
 }
 
-impl<T> From<sync::PoisonError<T>> for Error {
-    fn from(_source: sync::PoisonError<T>) -> Self {
-        Self::InternalError
+impl From<xid::Error> for Error {
+    fn from(source: xid::Error) -> Self {
+        Self::CookieInvalid(source)
+    }
+}
+
+impl From<store::Error> for Error {
+    fn from(source: store::Error) -> Self {
+        match source {
+            store::Error::Connection => Self::StoreUnavailable,
+            _ => Self::InternalError,
+        }
     }
 }
@@ -0,0 +1,246 @@
+use std::fmt;
+use std::sync;
+
+use actix_http::error::ResponseError;
+use actix_session::Session;
+use actix_web::{get, http, post, web, HttpResponse, Responder};
+use schemars::JsonSchema;
+
+use super::error::{self, ApiError};
+use super::{name, xid};
+use crate::store;
+
+/// The parameters passed in the path.
+#[derive(Deserialize)]
+pub struct Path {
+    /// The name of the message.
+    message_name: String,
+}
+
+/// A request to resume an exploration.
+#[derive(Debug, Deserialize, JsonSchema, Serialize)]
+#[schemars(rename = "ResumeRequest")]
+pub struct Request {
+    /// The exploration token.
+    token: String,
+}
+
+/// A description of a resumable exploration.
+#[derive(Debug, Deserialize, JsonSchema, Serialize)]
+#[schemars(rename = "ResumeToken")]
+pub struct Token {
+    /// The exploration token.
+    token: String,
+}
+
+/// The state of a resumed exploration.
+#[derive(Debug, JsonSchema, Serialize)]
+#[schemars(rename = "Exploration")]
+pub struct Response {
+    /// The current room.
+    room: super::Room,
+
+    /// The visited rooms, in the order they were first visited.
+    visited: Vec<super::Room>,
+}
+
+/// The possible error values.
+#[derive(Debug)]
+pub enum Error {
+    /// Explorations cannot be resumed.
+    Disabled,
+
+    /// The message is unknown.
+    UnknownMessage,
+
+    /// The message name is invalid.
+    NameInvalid(name::Error),
+
+    /// The exploration is unknown.
+    UnknownExploration,
+
+    /// The identifier cookie is invalid.
+    CookieInvalid(xid::Error),
+
+    /// The store cannot be reached.
+    StoreUnavailable,
+
+    /// An internal error occurred.
+    InternalError,
+}
+
+/// Reads the token under which the current exploration of a message is
+/// persisted.
+///
+/// A token is issued once the explorer has moved at least once.
+#[get("/{message_name}/resume")]
+pub async fn token(
+    path: web::Path<Path>,
+    navigation: web::Data<super::Navigation>,
+    session: Session,
+) -> impl Responder {
+    let name = path.message_name.parse::<name::Name>()?;
+
+    if !navigation.resume {
+        Err(Error::Disabled)
+    } else {
+        super::load_token(&session, name.as_str())
+            .map(|token| web::Json(Token { token }))
+            .ok_or(Error::UnknownExploration)
+    }
+}
+
+/// Resumes a persisted exploration in the current session.
+///
+/// # Arguments
+/// *  `req` - The token of the exploration to resume.
+#[post("/{message_name}/resume")]
+pub async fn handle(
+    path: web::Path<Path>,
+    req: web::Json<Request>,
+    store: web::Data<sync::Arc<dyn store::Store>>,
+    navigation: web::Data<super::Navigation>,
+    key: web::Data<xid::Key>,
+    session: Session,
+) -> impl Responder {
+    let name = path.message_name.parse::<name::Name>()?;
+
+    if !navigation.resume {
+        return Err(Error::Disabled);
+    }
+
+    let req = req.into_inner();
+    let store = store.get_ref().clone();
+    let message_name = name.clone();
+    let token = req.token.clone();
+    let max_lifetime = navigation.max_lifetime;
+    let max_age = navigation.max_age;
+    let (exploration, nonce, response, private) =
+        super::block(move || -> Result<_, Error> {
+            if !store.exists(message_name.as_str())? {
+                return Err(Error::UnknownMessage);
+            }
+
+            let exploration =
+                super::resume(store.as_ref(), message_name.as_str(), &token)?
+                    .ok_or(Error::UnknownExploration)?;
+            let room = store
+                .get(message_name.as_str(), Some(exploration.current))?
+                .ok_or(Error::UnknownExploration)?;
+            let visited = store.get_rooms(
+                message_name.as_str(),
+                &store.get_visited(&token)?,
+            )?;
+            super::touch(store.as_ref(), message_name.as_str(), max_lifetime)?;
+            let private = store.is_private(message_name.as_str())?;
+            let nonce = super::issue_nonce(store.as_ref(), max_age)?;
+
+            Ok((exploration, nonce, Response { room, visited }, private))
+        })
+        .await?;
+
+    // The resumed position shares the sequence number of the exploration, so
+    // it cannot move if another position has moved since
+    super::store_id(&session, name.as_str(), exploration.current, nonce)?;
+    super::store_token(&session, name.as_str(), req.token)?;
+    super::store_sequence(&session, name.as_str(), exploration.sequence)?;
+    if private {
+        let key = super::session_key(&session, &key)?;
+        Ok(web::Json(Response {
+            room: response.room.encrypt(&key),
+            visited: response
+                .visited
+                .into_iter()
+                .map(|room| room.encrypt(&key))
+                .collect(),
+        }))
+    } else {
+        Ok(web::Json(response))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Disabled => write!(f, "resuming is disabled"),
+            Error::UnknownMessage => write!(f, "unknown message"),
+            Error::NameInvalid(e) => write!(f, "name invalid: {}", e),
+            Error::UnknownExploration => write!(f, "unknown exploration"),
+            Error::CookieInvalid(e) => write!(f, "cookie invalid: {}", e),
+            Error::StoreUnavailable => write!(f, "store unavailable"),
+            Error::InternalError => write!(f, "internal error"),
+        }
+    }
+}
+
+impl ResponseError for Error {
+    fn status_code(&self) -> http::StatusCode {
+        match self {
+            Error::Disabled => http::StatusCode::NOT_FOUND,
+            Error::UnknownMessage => http::StatusCode::NOT_FOUND,
+            Error::NameInvalid(_) => http::StatusCode::BAD_REQUEST,
+            Error::UnknownExploration => http::StatusCode::NOT_FOUND,
+            Error::CookieInvalid(_) => http::StatusCode::BAD_REQUEST,
+            Error::StoreUnavailable => http::StatusCode::SERVICE_UNAVAILABLE,
+            Error::InternalError => http::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        error::respond(self)
+    }
+}
+
+impl ApiError for Error {
+    fn all() -> Vec<Self> {
+        vec![
+            Error::Disabled,
+            Error::UnknownMessage,
+            Error::NameInvalid(name::Error::TooShort),
+            Error::UnknownExploration,
+            Error::CookieInvalid(xid::Error::Format),
+            Error::StoreUnavailable,
+            Error::InternalError,
+        ]
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            Error::Disabled => "disabled",
+            Error::UnknownMessage => "unknown_message",
+            Error::NameInvalid(_) => "name_invalid",
+            Error::UnknownExploration => "unknown_exploration",
+            Error::CookieInvalid(_) => "cookie_invalid",
+            Error::StoreUnavailable => "store_unavailable",
+            Error::InternalError => "internal_error",
+        }
+    }
+
+    fn details(&self) -> Option<serde_json::Value> {
+        match self {
+            Error::NameInvalid(e) => error::name_details(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<name::Error> for Error {
+    fn from(source: name::Error) -> Self {
+        Self::NameInvalid(source)
+    }
+}
+
+impl From<xid::Error> for Error {
+    fn from(source: xid::Error) -> Self {
+        Self::CookieInvalid(source)
+    }
+}
+
+impl From<store::Error> for Error {
+    fn from(source: store::Error) -> Self {
+        match source {
+            store::Error::Connection => Self::StoreUnavailable,
+            _ => Self::InternalError,
+        }
+    }
+}
@@ -3,9 +3,11 @@ use std::sync;
 
 use actix_http::error::ResponseError;
 use actix_session::Session;
-use actix_web::{http, put, web, Responder};
+use actix_web::{http, put, web, HttpResponse, Responder};
+use schemars::JsonSchema;
 
-use super::xid;
+use super::error::{self, ApiError};
+use super::{name, xid};
 use crate::store;
 
 /// The parameters passed in the path.
@@ -16,7 +18,8 @@ pub struct Path {
 }
 
 /// A request to create a message.
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, JsonSchema, Serialize)]
+#[schemars(rename = "UpdateRequest")]
 pub struct Request {
     /// The identifier of the room to which to move.
     xid: xid::Identifier,
@@ -27,11 +30,30 @@ pub enum Error {
     /// The message name is invalid.
     UnknownMessage,
 
+    /// The message name is invalid.
+    NameInvalid(name::Error),
+
     /// The room is unknown.
     UnknownRoom,
 
     /// The specified transition is illegal.
-    IllegalTransition,
+    IllegalTransition {
+        /// The identifier of the room in which the explorer remains.
+        current: xid::Identifier,
+    },
+
+    /// The identifier cookie is invalid.
+    CookieInvalid(xid::Error),
+
+    /// The identifier cookie has already been used to move.
+    StaleCookie,
+
+    /// The current room no longer exists, since the message has been
+    /// regenerated; the explorer must start over from the entrance.
+    PositionReset,
+
+    /// The store cannot be reached.
+    StoreUnavailable,
 
     /// An internal error occurred.
     InternalError,
@@ -41,38 +63,146 @@ pub enum Error {
 pub async fn handle(
     path: web::Path<Path>,
     req: web::Json<Request>,
-    store: web::Data<sync::Arc<sync::Mutex<store::Store>>>,
+    store: web::Data<sync::Arc<dyn store::Store>>,
+    navigation: web::Data<super::Navigation>,
+    key: web::Data<xid::Key>,
     session: Session,
 ) -> impl Responder {
-    let mut store = store.lock()?;
-
-    if !store.exists(&path.message_name)? {
-        Err(Error::UnknownMessage)
-    } else {
-        let current_id = match super::load_id(&session) {
-            Ok(id) => Some(id),
+    let name = path.message_name.parse::<name::Name>()?;
+    let current =
+        match super::load_cookie(&session, name.as_str(), navigation.max_age) {
+            Ok(cookie) => Some(cookie),
             Err(xid::Error::Expired) | Err(xid::Error::Missing) => None,
             Err(e) => return Err(e.into()),
         };
-        let next_id = req.xid;
-        let current_room = store
-            .get(&path.message_name, current_id)?
-            .ok_or(Error::UnknownRoom)?;
-
-        if current_room.see.iter().find(|&&id| id == next_id).is_some() {
-            super::store_id(&session, next_id)?;
-            store
-                .get(&path.message_name, Some(next_id))?
-                .ok_or(Error::UnknownRoom)
-                .map(web::Json)
+    let token = if navigation.resume {
+        Some(
+            super::load_token(&session, name.as_str())
+                .unwrap_or_else(super::generate_token),
+        )
+    } else {
+        None
+    };
+    let sequence = super::load_sequence(&session, name.as_str());
+
+    let store = store.get_ref().clone();
+    let message_name = name.clone();
+    let private = {
+        let store = store.clone();
+        super::block(move || -> Result<_, Error> {
+            if !store.exists(message_name.as_str())? {
+                return Err(Error::UnknownMessage);
+            }
+            Ok(store.is_private(message_name.as_str())?)
+        })
+        .await?
+    };
+
+    // The identifiers of private messages are only known to the client in
+    // encrypted form
+    let session_key = if private {
+        Some(super::session_key(&session, &key)?)
+    } else {
+        None
+    };
+
+    let message_name = name.clone();
+    let requested_id = req.xid;
+    let decryption_key = session_key.clone();
+    let persisted_token = token.clone();
+    let max_lifetime = navigation.max_lifetime;
+    let max_age = navigation.max_age;
+    let result = super::block(move || -> Result<_, Error> {
+        // Every cookie can be used for a single move only
+        let used_nonce = match &current {
+            Some(cookie) if !store.take_nonce(cookie.nonce())? => {
+                return Err(Error::StaleCookie);
+            }
+            Some(cookie) => Some(cookie.nonce().to_string()),
+            None => None,
+        };
+        let current_id = current.map(xid::Identifier::from);
+
+        let next_id = match &decryption_key {
+            Some(key) => key.decrypt(requested_id),
+            None => requested_id,
+        };
+
+        // The moves of a persisted exploration are numbered, and a position
+        // may only move if no other move has been made since it was entered;
+        // positions stored before moves were numbered need a valid cookie
+        let exploration = match &persisted_token {
+            Some(token) => {
+                super::resume(store.as_ref(), message_name.as_str(), token)?
+            }
+            None => None,
+        };
+        let moves =
+            exploration.as_ref().map(|exploration| exploration.sequence);
+        match (moves, sequence, &current) {
+            (Some(moves), Some(sequence), _) if moves != sequence => {
+                return Err(Error::StaleCookie);
+            }
+            (Some(_), None, None) => return Err(Error::StaleCookie),
+            _ => {}
+        }
+        let moves = moves.unwrap_or(0);
+
+        let current_id =
+            current_id.or(exploration.map(|exploration| exploration.current));
+        let current_room = match store.get(message_name.as_str(), current_id)? {
+            Some(room) => room,
+            None if current_id.is_some() => return Err(Error::PositionReset),
+            None => return Err(Error::UnknownRoom),
+        };
+
+        if current_room.see.contains(&next_id) {
+            if let Some(token) = &persisted_token {
+                if !store.visit(token, message_name.as_str(), moves, next_id)? {
+                    return Err(Error::StaleCookie);
+                }
+            }
+            let room = store
+                .get(message_name.as_str(), Some(next_id))?
+                .ok_or(Error::UnknownRoom)?;
+            super::touch(store.as_ref(), message_name.as_str(), max_lifetime)?;
+            let nonce = super::issue_nonce(store.as_ref(), max_age)?;
+            Ok((room, next_id, nonce, moves + 1))
         } else {
             log::info!(
                 "Cannot transition from {:?} to {}",
                 current_id,
                 next_id
             );
-            Err(Error::IllegalTransition)
+
+            // A failed move does not use up the cookie
+            if let Some(nonce) = used_nonce {
+                store.put_nonce(&nonce, max_age)?;
+            }
+            Err(Error::IllegalTransition {
+                current: match &decryption_key {
+                    Some(key) => key.encrypt(current_room.xid),
+                    None => current_room.xid,
+                },
+            })
         }
+    })
+    .await;
+
+    // A stale position is dropped, so that the explorer can start over
+    if let Err(Error::StaleCookie) | Err(Error::PositionReset) = result {
+        super::clear_id(&session, name.as_str());
+    }
+    let (room, next_id, nonce, sequence) = result?;
+
+    super::store_id(&session, name.as_str(), next_id, nonce)?;
+    if let Some(token) = token {
+        super::store_token(&session, name.as_str(), token)?;
+        super::store_sequence(&session, name.as_str(), sequence)?;
+    }
+    match session_key {
+        Some(key) => Ok(web::Json(room.encrypt(&key))),
+        None => Ok(web::Json(room)),
     }
 }
 
@@ -80,8 +210,13 @@ impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Error::UnknownMessage => write!(f, "unknown message"),
+            Error::NameInvalid(e) => write!(f, "name invalid: {}", e),
             Error::UnknownRoom => write!(f, "unknown room"),
-            Error::IllegalTransition => write!(f, "illegal transition"),
+            Error::IllegalTransition { .. } => write!(f, "illegal transition"),
+            Error::CookieInvalid(e) => write!(f, "cookie invalid: {}", e),
+            Error::StaleCookie => write!(f, "stale identifier cookie"),
+            Error::PositionReset => write!(f, "position reset"),
+            Error::StoreUnavailable => write!(f, "store unavailable"),
             Error::InternalError => write!(f, "internal error"),
         }
     }
@@ -91,27 +226,81 @@ impl ResponseError for Error {
     fn status_code(&self) -> http::StatusCode {
         match self {
             Error::UnknownMessage => http::StatusCode::NOT_FOUND,
+            Error::NameInvalid(_) => http::StatusCode::BAD_REQUEST,
             Error::UnknownRoom => http::StatusCode::NOT_FOUND,
-            Error::IllegalTransition => http::StatusCode::NOT_FOUND,
+            Error::IllegalTransition { .. } => http::StatusCode::FORBIDDEN,
+            Error::CookieInvalid(_) => http::StatusCode::BAD_REQUEST,
+            Error::StaleCookie => http::StatusCode::CONFLICT,
+            Error::PositionReset => http::StatusCode::CONFLICT,
+            Error::StoreUnavailable => http::StatusCode::SERVICE_UNAVAILABLE,
             Error::InternalError => http::StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
+
+    fn error_response(&self) -> HttpResponse {
+        error::respond(self)
+    }
 }
 
-impl From<xid::Error> for Error {
-    fn from(_: xid::Error) -> Self {
-        Self::UnknownRoom
+impl ApiError for Error {
+    fn all() -> Vec<Self> {
+        vec![
+            Error::UnknownMessage,
+            Error::NameInvalid(name::Error::TooShort),
+            Error::UnknownRoom,
+            Error::IllegalTransition {
+                current: xid::Identifier::default(),
+            },
+            Error::CookieInvalid(xid::Error::Format),
+            Error::StaleCookie,
+            Error::PositionReset,
+            Error::StoreUnavailable,
+            Error::InternalError,
+        ]
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            Error::UnknownMessage => "unknown_message",
+            Error::NameInvalid(_) => "name_invalid",
+            Error::UnknownRoom => "unknown_room",
+            Error::IllegalTransition { .. } => "illegal_transition",
+            Error::CookieInvalid(_) => "cookie_invalid",
+            Error::StaleCookie => "stale_cookie",
+            Error::PositionReset => "position_reset",
+            Error::StoreUnavailable => "store_unavailable",
+            Error::InternalError => "internal_error",
+        }
+    }
+
+    fn details(&self) -> Option<serde_json::Value> {
+        match self {
+            Error::NameInvalid(e) => error::name_details(e),
+            Error::IllegalTransition { current } => {
+                Some(serde_json::json!({ "xid": current }))
+            }
+            _ => None,
+        }
     }
 }
 
-impl From<store::Error> for Error {
-    fn from(_source: store::Error) -> Self {
-        Self::InternalError
+impl From<name::Error> for Error {
+    fn from(source: name::Error) -> Self {
+        Self::NameInvalid(source)
     }
 }
 
-impl<T> From<sync::PoisonError<T>> for Error {
-    fn from(_source: sync::PoisonError<T>) -> Self {
-        Self::InternalError
+impl From<xid::Error> for Error {
+    fn from(source: xid::Error) -> Self {
+        Self::CookieInvalid(source)
+    }
+}
+
+impl From<store::Error> for Error {
+    fn from(source: store::Error) -> Self {
+        match source {
+            store::Error::Connection => Self::StoreUnavailable,
+            _ => Self::InternalError,
+        }
     }
 }
@@ -5,9 +5,13 @@ use std::time;
 
 use std::ops::Add;
 
+use hmac::{Hmac, Mac, NewMac};
+use schemars::schema::{InstanceType, Schema, SchemaObject, StringValidation};
 use serde;
+use sha2::Sha256;
 
 /// An identifier parse error.
+#[derive(Clone, Copy, Debug)]
 pub enum Error {
     /// The string format is invalid.
     Format,
@@ -23,7 +27,7 @@ pub enum Error {
 }
 
 /// A room identifier.
-#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
 pub struct Identifier(u64);
 
 impl From<u64> for Identifier {
@@ -74,6 +78,120 @@ impl serde::ser::Serialize for Identifier {
     }
 }
 
+impl schemars::JsonSchema for Identifier {
+    fn schema_name() -> String {
+        "Identifier".into()
+    }
+
+    /// Describes identifiers as hex strings.
+    fn json_schema(_: &mut schemars::gen::SchemaGenerator) -> Schema {
+        SchemaObject {
+            instance_type: Some(InstanceType::String.into()),
+            string: Some(Box::new(StringValidation {
+                pattern: Some("^[0-9A-Fa-f]{1,16}$".into()),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+/// A secret key from which room identifiers are derived.
+///
+/// Identifiers are derived through a keyed hash, so knowing some of them
+/// reveals nothing about the others.
+///
+/// A key can also encrypt identifiers, which permutes them in a way that
+/// cannot be reversed without the key.
+#[derive(Clone)]
+pub struct Key(Vec<u8>);
+
+impl Key {
+    /// Creates a key from a secret.
+    ///
+    /// # Arguments
+    /// *  `secret` - The server secret.
+    pub fn new(secret: &[u8]) -> Self {
+        Self(secret.to_vec())
+    }
+
+    /// The number of rounds used when encrypting identifiers.
+    const ROUNDS: u8 = 4;
+
+    /// Derives a new key from this one.
+    ///
+    /// # Arguments
+    /// *  `context` - The value distinguishing the derived key.
+    pub fn derive(&self, context: &[u8]) -> Self {
+        Self(self.mac(context).to_vec())
+    }
+
+    /// Encrypts an identifier.
+    ///
+    /// # Arguments
+    /// *  `id` - The identifier to encrypt.
+    pub fn encrypt(&self, id: Identifier) -> Identifier {
+        let (mut left, mut right) = ((id.0 >> 32) as u32, id.0 as u32);
+        for round in 0..Self::ROUNDS {
+            let next = left ^ self.round(round, right);
+            left = right;
+            right = next;
+        }
+        Identifier((u64::from(left) << 32) | u64::from(right))
+    }
+
+    /// Decrypts an identifier encrypted with [`Key::encrypt`].
+    ///
+    /// # Arguments
+    /// *  `id` - The identifier to decrypt.
+    pub fn decrypt(&self, id: Identifier) -> Identifier {
+        let (mut left, mut right) = ((id.0 >> 32) as u32, id.0 as u32);
+        for round in (0..Self::ROUNDS).rev() {
+            let previous = right ^ self.round(round, left);
+            right = left;
+            left = previous;
+        }
+        Identifier((u64::from(left) << 32) | u64::from(right))
+    }
+
+    /// Calculates the keyed hash of a value.
+    ///
+    /// # Arguments
+    /// *  `value` - The value to hash.
+    fn mac(&self, value: &[u8]) -> [u8; 32] {
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.0)
+            .expect("HMAC accepts keys of any length");
+        mac.update(value);
+        let mut result = [0u8; 32];
+        result.copy_from_slice(&mac.finalize().into_bytes());
+        result
+    }
+
+    /// The round function of the Feistel network used to encrypt
+    /// identifiers.
+    ///
+    /// # Arguments
+    /// *  `round` - The round number.
+    /// *  `half` - The half of the identifier to mix.
+    fn round(&self, round: u8, half: u32) -> u32 {
+        let mut value = [round; 5];
+        value[1..].copy_from_slice(&half.to_be_bytes());
+        let mac = self.mac(&value);
+        u32::from_be_bytes([mac[0], mac[1], mac[2], mac[3]])
+    }
+
+    /// Derives an identifier from a value.
+    ///
+    /// # Arguments
+    /// *  `value` - The value from which to derive the identifier.
+    pub fn identifier(&self, value: u64) -> Identifier {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&self.mac(&value.to_be_bytes())[..8]);
+        Identifier(u64::from_be_bytes(bytes))
+    }
+}
+
 impl From<IdentifierCookie> for Identifier {
     fn from(source: IdentifierCookie) -> Self {
         source.0
@@ -84,19 +202,33 @@ impl From<IdentifierCookie> for Identifier {
 ///
 /// Identifiers wrapped in cookie values provide timestamps that are checked
 /// when parsing and generated when stringified. Parsing will fail for
-/// timestamps generated too far in the past.
-pub struct IdentifierCookie(Identifier);
+/// timestamps generated more than a maximum age in the past.
+///
+/// Every cookie value also carries a nonce, which the server records when
+/// issuing the cookie and consumes when the cookie is used to move, so that
+/// a cookie cannot be replayed.
+pub struct IdentifierCookie(Identifier, String);
 
 impl IdentifierCookie {
     /// The separator used in the cookie value.
     const SEPARATOR: char = ':';
 
-    /// The maximum age of a cookie.
-    const MAX_AGE: time::Duration = time::Duration::from_secs(10);
-}
+    /// The default maximum age of a cookie.
+    pub const DEFAULT_MAX_AGE: time::Duration = time::Duration::from_secs(10);
 
-impl str::FromStr for IdentifierCookie {
-    type Err = Error;
+    /// Wraps an identifier.
+    ///
+    /// # Arguments
+    /// *  `id` - The identifier.
+    /// *  `nonce` - The nonce recorded for this cookie.
+    pub fn new(id: Identifier, nonce: String) -> Self {
+        Self(id, nonce)
+    }
+
+    /// The nonce recorded for this cookie.
+    pub fn nonce(&self) -> &str {
+        &self.1
+    }
 
     /// Parses an identifier cookie value.
     ///
@@ -104,11 +236,12 @@ impl str::FromStr for IdentifierCookie {
     ///
     /// # Arguments
     /// *  `source` - The string to parse.
-    fn from_str(source: &str) -> Result<Self, Self::Err> {
+    /// *  `max_age` - The maximum age of the cookie.
+    pub fn parse(source: &str, max_age: time::Duration) -> Result<Self, Error> {
         let mut parts = source.split(Self::SEPARATOR);
         let xid = parts
             .next()
-            .and_then(|s| Identifier::from_str(s).ok())
+            .and_then(|s| s.parse::<Identifier>().ok())
             .ok_or(Error::Format)?;
         let then = time::UNIX_EPOCH.add(
             parts
@@ -117,8 +250,12 @@ impl str::FromStr for IdentifierCookie {
                 .map(time::Duration::from_millis)
                 .ok_or(Error::Timestamp)?,
         );
+        let nonce = parts
+            .next()
+            .filter(|s| !s.is_empty())
+            .ok_or(Error::Format)?;
         match time::SystemTime::now().duration_since(then) {
-            Ok(d) if d < Self::MAX_AGE => Ok(xid.into()),
+            Ok(d) if d < max_age => Ok(Self(xid, nonce.into())),
             _ => Err(Error::Expired),
         }
     }
@@ -126,23 +263,134 @@ impl str::FromStr for IdentifierCookie {
 
 impl fmt::Display for IdentifierCookie {
     /// Displays this identifier as a zero padded hex string with a timestamp
-    /// appended.
+    /// and the nonce appended.
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "{}{}{:?}",
+            "{}{}{:?}{}{}",
             self.0,
             Self::SEPARATOR,
             time::SystemTime::now()
                 .duration_since(time::UNIX_EPOCH)
                 .unwrap()
                 .as_millis(),
+            Self::SEPARATOR,
+            self.1,
         )
     }
 }
 
-impl From<Identifier> for IdentifierCookie {
-    fn from(source: Identifier) -> Self {
-        IdentifierCookie(source)
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Format => write!(f, "invalid format"),
+            Error::Timestamp => write!(f, "invalid timestamp"),
+            Error::Expired => write!(f, "expired"),
+            Error::Missing => write!(f, "missing"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The current time, in milliseconds since the epoch.
+    fn now() -> u128 {
+        time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis()
+    }
+
+    #[test]
+    fn encrypt_round_trip() {
+        let key = Key::new(b"secret");
+        for &value in &[0, 1, 0xDEAD_BEEF, u64::MAX] {
+            let id = Identifier(value);
+            let encrypted = key.encrypt(id);
+            assert_ne!(encrypted, id);
+            assert_eq!(key.decrypt(encrypted), id);
+        }
+    }
+
+    #[test]
+    fn encrypt_depends_on_key() {
+        let id = Identifier(42);
+        let key = Key::new(b"secret");
+        let other = key.derive(b"session");
+        assert_ne!(key.encrypt(id), other.encrypt(id));
+        assert_ne!(other.decrypt(key.encrypt(id)), id);
+    }
+
+    #[test]
+    fn identifier_round_trip() {
+        let id = Identifier(0x0123_4567_89AB_CDEF);
+        assert_eq!(id.to_string(), "0123456789ABCDEF");
+        assert_eq!(id.to_string().parse::<Identifier>().unwrap(), id);
+    }
+
+    #[test]
+    fn identifiers_depend_on_key() {
+        let key = Key::new(b"secret");
+        assert_eq!(key.identifier(1), Key::new(b"secret").identifier(1));
+        assert_ne!(key.identifier(1), key.identifier(2));
+        assert_ne!(key.identifier(1), Key::new(b"other").identifier(1));
+    }
+
+    #[test]
+    fn cookie_round_trip() {
+        let id = Identifier(42);
+        let cookie = IdentifierCookie::new(id, "nonce".into()).to_string();
+        let parsed =
+            IdentifierCookie::parse(&cookie, IdentifierCookie::DEFAULT_MAX_AGE)
+                .unwrap();
+        assert_eq!(parsed.nonce(), "nonce");
+        assert_eq!(Identifier::from(parsed), id);
+    }
+
+    #[test]
+    fn cookie_expires() {
+        let id = Identifier(42);
+        let max_age = IdentifierCookie::DEFAULT_MAX_AGE;
+        let then = now() - max_age.as_millis() - 1;
+        assert!(matches!(
+            IdentifierCookie::parse(&format!("{}:{}:nonce", id, then), max_age),
+            Err(Error::Expired)
+        ));
+        assert!(matches!(
+            IdentifierCookie::parse(
+                &IdentifierCookie::new(id, "nonce".into()).to_string(),
+                time::Duration::from_millis(0),
+            ),
+            Err(Error::Expired)
+        ));
+    }
+
+    #[test]
+    fn cookie_requires_nonce() {
+        let id = Identifier(42);
+        let max_age = IdentifierCookie::DEFAULT_MAX_AGE;
+        assert!(matches!(
+            IdentifierCookie::parse(&format!("{}:{}", id, now()), max_age),
+            Err(Error::Format)
+        ));
+        assert!(matches!(
+            IdentifierCookie::parse(&format!("{}:{}:", id, now()), max_age),
+            Err(Error::Format)
+        ));
+    }
+
+    #[test]
+    fn cookie_requires_timestamp() {
+        let max_age = IdentifierCookie::DEFAULT_MAX_AGE;
+        assert!(matches!(
+            IdentifierCookie::parse("2A:never:nonce", max_age),
+            Err(Error::Timestamp)
+        ));
+        assert!(matches!(
+            IdentifierCookie::parse("room:0:nonce", max_age),
+            Err(Error::Format)
+        ));
     }
 }
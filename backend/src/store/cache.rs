@@ -0,0 +1,569 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync;
+use std::time;
+
+use crate::messages;
+use crate::messages::xid;
+
+use super::lru::Lru;
+use super::{Error, Options, Store, Watcher};
+
+/// The key of a cached room.
+type RoomKey = (String, Option<xid::Identifier>);
+
+/// A store keeping recently read rooms in process memory.
+///
+/// Cached records never outlive the messages they belong to, and are
+/// ignored once the underlying store reports that a message has been deleted
+/// or replaced. Records read before such a report are never used after it.
+pub struct CachedStore {
+    /// The underlying store.
+    store: sync::Arc<dyn Store>,
+
+    /// The maximum time for which a record is cached.
+    ttl: time::Duration,
+
+    /// The invalidations of cached records.
+    generations: sync::Mutex<Generations>,
+
+    /// The expiry times of known messages and the generations in which
+    /// they were read, keyed on name.
+    messages: sync::Mutex<Lru<String, (u64, time::Instant)>>,
+
+    /// The cached rooms, the generations in which they were read and their
+    /// expiry times.
+    rooms: sync::Mutex<Lru<RoomKey, (u64, time::Instant, messages::Room)>>,
+}
+
+/// The invalidations of cached records.
+///
+/// Every invalidation starts a new generation. Records read during an
+/// earlier generation than the last invalidation of their message are
+/// stale, so invalidating a message never has to find its records.
+#[derive(Default)]
+struct Generations {
+    /// The current generation.
+    current: u64,
+
+    /// The generation in which all records were last invalidated.
+    cleared: u64,
+
+    /// The generations in which messages were last invalidated, and the
+    /// times at which this happened, keyed on name.
+    messages: HashMap<String, (u64, time::Instant)>,
+
+    /// The invalidations of single messages, oldest first.
+    invalidations: VecDeque<(u64, time::Instant, String)>,
+}
+
+impl Generations {
+    /// Determines whether records of a message read during a generation
+    /// have since been invalidated.
+    ///
+    /// # Arguments
+    /// *  `message_name` - The name of the message.
+    /// *  `generation` - The generation during which the records were read.
+    fn is_stale(&self, message_name: &str, generation: u64) -> bool {
+        self.cleared > generation
+            || self
+                .messages
+                .get(message_name)
+                .map_or(false, |&(invalidated, _)| invalidated > generation)
+    }
+
+    /// Starts a new generation, invalidating the records of a message.
+    ///
+    /// # Arguments
+    /// *  `message_name` - The name of the message, or `None` to invalidate
+    ///    all records.
+    /// *  `ttl` - The maximum time for which a record is cached.
+    fn invalidate(&mut self, message_name: Option<&str>, ttl: time::Duration) {
+        self.current += 1;
+        match message_name {
+            Some(message_name) => {
+                // Records read longer than the TTL ago have expired by now,
+                // so older invalidations need not be remembered
+                let now = time::Instant::now();
+                while self
+                    .invalidations
+                    .front()
+                    .map_or(false, |&(_, invalidated, _)| {
+                        invalidated + ttl <= now
+                    })
+                {
+                    if let Some((generation, _, name)) =
+                        self.invalidations.pop_front()
+                    {
+                        // A later invalidation of the message is still needed
+                        if self.messages.get(&name).map(|&(g, _)| g)
+                            == Some(generation)
+                        {
+                            self.messages.remove(&name);
+                        }
+                    }
+                }
+                self.messages
+                    .insert(message_name.into(), (self.current, now));
+                self.invalidations.push_back((
+                    self.current,
+                    now,
+                    message_name.into(),
+                ));
+            }
+            None => {
+                self.cleared = self.current;
+                self.messages.clear();
+                self.invalidations.clear();
+            }
+        }
+    }
+}
+
+impl CachedStore {
+    /// Creates a new store.
+    ///
+    /// # Arguments
+    /// *  `store` - The underlying store.
+    /// *  `size` - The maximum number of cached rooms.
+    /// *  `ttl` - The maximum time for which a record is cached.
+    pub fn new(
+        store: sync::Arc<dyn Store>,
+        size: usize,
+        ttl: time::Duration,
+    ) -> Result<sync::Arc<Self>, Error> {
+        let result = sync::Arc::new(Self {
+            store,
+            ttl,
+            generations: sync::Mutex::new(Generations::default()),
+            messages: sync::Mutex::new(Lru::new(size)),
+            rooms: sync::Mutex::new(Lru::new(size)),
+        });
+
+        let weak = sync::Arc::downgrade(&result);
+        result.store.watch(Box::new(move |message_name| {
+            if let Some(cache) = weak.upgrade() {
+                cache.invalidate(message_name);
+            }
+        }))?;
+
+        Ok(result)
+    }
+
+    /// Invalidates all cached records of a message.
+    ///
+    /// This takes constant time; stale records are dropped when next read.
+    ///
+    /// # Arguments
+    /// *  `message_name` - The name of the message, or `None` to invalidate
+    ///    all records.
+    fn invalidate(&self, message_name: Option<&str>) {
+        if let Ok(mut generations) = self.generations.lock() {
+            generations.invalidate(message_name, self.ttl);
+        }
+
+        // Dropping everything is rare, and frees the memory at once
+        if message_name.is_none() {
+            if let (Ok(mut messages), Ok(mut rooms)) =
+                (self.messages.lock(), self.rooms.lock())
+            {
+                messages.clear();
+                rooms.clear();
+            }
+        }
+    }
+
+    /// The current generation, to be read before reading records to cache.
+    fn generation(&self) -> Result<u64, Error> {
+        Ok(self
+            .generations
+            .lock()
+            .map_err(|_| Error::InternalError)?
+            .current)
+    }
+
+    /// Determines whether records of a message read during a generation
+    /// have since been invalidated.
+    ///
+    /// # Arguments
+    /// *  `message_name` - The name of the message.
+    /// *  `generation` - The generation during which the records were read.
+    fn is_stale(
+        &self,
+        message_name: &str,
+        generation: u64,
+    ) -> Result<bool, Error> {
+        Ok(self
+            .generations
+            .lock()
+            .map_err(|_| Error::InternalError)?
+            .is_stale(message_name, generation))
+    }
+
+    /// Caches records of a message unless the message has been invalidated
+    /// since they were read.
+    ///
+    /// Invalidations wait until the records have been cached.
+    ///
+    /// # Arguments
+    /// *  `message_name` - The name of the message.
+    /// *  `generation` - The generation read before reading the records.
+    /// *  `f` - The function caching the records.
+    fn cache<F>(
+        &self,
+        message_name: &str,
+        generation: u64,
+        f: F,
+    ) -> Result<(), Error>
+    where
+        F: FnOnce() -> Result<(), Error>,
+    {
+        let generations =
+            self.generations.lock().map_err(|_| Error::InternalError)?;
+        if generations.is_stale(message_name, generation) {
+            Ok(())
+        } else {
+            f()
+        }
+    }
+
+    /// Determines the time until which records of a message may be cached.
+    ///
+    /// If the message does not exist, `None` is returned.
+    ///
+    /// # Arguments
+    /// *  `message_name` - The name of the message.
+    /// *  `generation` - The generation read before calling this method.
+    fn expires(
+        &self,
+        message_name: &str,
+        generation: u64,
+    ) -> Result<Option<time::Instant>, Error> {
+        let now = time::Instant::now();
+        let cached = self
+            .messages
+            .lock()
+            .map_err(|_| Error::InternalError)?
+            .get(message_name)
+            .filter(|&(_, expires)| expires > now);
+        if let Some((read, expires)) = cached {
+            if !self.is_stale(message_name, read)? {
+                return Ok(Some(expires));
+            }
+            self.messages
+                .lock()
+                .map_err(|_| Error::InternalError)?
+                .remove(message_name);
+        }
+
+        match self.store.expires_in(message_name)? {
+            Some(remaining) => {
+                let expires = now + remaining.min(self.ttl);
+                self.cache(message_name, generation, || {
+                    self.messages
+                        .lock()
+                        .map_err(|_| Error::InternalError)?
+                        .insert(message_name.into(), (generation, expires));
+                    Ok(())
+                })?;
+                Ok(Some(expires))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl Store for CachedStore {
+    fn get(
+        &self,
+        message_name: &str,
+        id: Option<xid::Identifier>,
+    ) -> Result<Option<messages::Room>, Error> {
+        let now = time::Instant::now();
+        let key = (message_name.to_string(), id);
+        let cached = self
+            .rooms
+            .lock()
+            .map_err(|_| Error::InternalError)?
+            .get(&key)
+            .filter(|(_, expires, _)| *expires > now);
+        if let Some((read, _, room)) = cached {
+            if !self.is_stale(message_name, read)? {
+                return Ok(Some(room));
+            }
+            self.rooms
+                .lock()
+                .map_err(|_| Error::InternalError)?
+                .remove(&key);
+        }
+
+        let generation = self.generation()?;
+        let expires = match self.expires(message_name, generation)? {
+            Some(expires) => expires,
+            None => return Ok(None),
+        };
+        let room = self.store.get(message_name, id)?;
+        if let Some(room) = &room {
+            self.cache(message_name, generation, || {
+                self.rooms
+                    .lock()
+                    .map_err(|_| Error::InternalError)?
+                    .insert(key, (generation, expires, room.clone()));
+                Ok(())
+            })?;
+        }
+        Ok(room)
+    }
+
+    fn get_rooms(
+        &self,
+        message_name: &str,
+        ids: &[xid::Identifier],
+    ) -> Result<Vec<messages::Room>, Error> {
+        self.store.get_rooms(message_name, ids)
+    }
+
+    fn exists(&self, message_name: &str) -> Result<bool, Error> {
+        let generation = self.generation()?;
+        Ok(self.expires(message_name, generation)?.is_some())
+    }
+
+    fn expires_in(
+        &self,
+        message_name: &str,
+    ) -> Result<Option<time::Duration>, Error> {
+        self.store.expires_in(message_name)
+    }
+
+    fn touch(
+        &self,
+        message_name: &str,
+        max_lifetime: time::Duration,
+    ) -> Result<(), Error> {
+        self.store.touch(message_name, max_lifetime)
+    }
+
+    fn put_message(
+        &self,
+        message: &messages::Message,
+        options: &Options,
+    ) -> Result<(), Error> {
+        self.store.put_message(message, options)?;
+        self.invalidate(Some(message.name()));
+        Ok(())
+    }
+
+    fn get_recipe(
+        &self,
+        message_name: &str,
+    ) -> Result<Option<messages::Recipe>, Error> {
+        self.store.get_recipe(message_name)
+    }
+
+    fn put_recipe(
+        &self,
+        message: &messages::Message,
+        options: &Options,
+    ) -> Result<(), Error> {
+        self.store.put_recipe(message, options)?;
+        self.invalidate(Some(message.name()));
+        Ok(())
+    }
+
+    fn replace_message(
+        &self,
+        message: &messages::Message,
+        owner: &str,
+    ) -> Result<(), Error> {
+        self.store.replace_message(message, owner)?;
+        self.invalidate(Some(message.name()));
+        Ok(())
+    }
+
+    fn replace_recipe(
+        &self,
+        message: &messages::Message,
+        owner: &str,
+    ) -> Result<(), Error> {
+        self.store.replace_recipe(message, owner)?;
+        self.invalidate(Some(message.name()));
+        Ok(())
+    }
+
+    fn get_owner(&self, message_name: &str) -> Result<Option<String>, Error> {
+        self.store.get_owner(message_name)
+    }
+
+    fn is_private(&self, message_name: &str) -> Result<bool, Error> {
+        self.store.is_private(message_name)
+    }
+
+    fn delete(&self, message_name: &str, owner: &str) -> Result<bool, Error> {
+        let deleted = self.store.delete(message_name, owner)?;
+        self.invalidate(Some(message_name));
+        Ok(deleted)
+    }
+
+    fn get_summary(
+        &self,
+        message_name: &str,
+    ) -> Result<Option<(messages::Summary, time::SystemTime)>, Error> {
+        self.store.get_summary(message_name)
+    }
+
+    fn get_exploration(
+        &self,
+        token: &str,
+    ) -> Result<Option<messages::Exploration>, Error> {
+        self.store.get_exploration(token)
+    }
+
+    fn get_visited(&self, token: &str) -> Result<Vec<xid::Identifier>, Error> {
+        self.store.get_visited(token)
+    }
+
+    fn visit(
+        &self,
+        token: &str,
+        message_name: &str,
+        sequence: u64,
+        id: xid::Identifier,
+    ) -> Result<bool, Error> {
+        self.store.visit(token, message_name, sequence, id)
+    }
+
+    fn put_nonce(&self, nonce: &str, ttl: time::Duration) -> Result<(), Error> {
+        self.store.put_nonce(nonce, ttl)
+    }
+
+    fn take_nonce(&self, nonce: &str) -> Result<bool, Error> {
+        self.store.take_nonce(nonce)
+    }
+
+    fn watch(&self, watcher: Watcher) -> Result<(), Error> {
+        self.store.watch(watcher)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use maze::initialize;
+
+    use super::*;
+    use crate::store::MemoryStore;
+
+    /// Generates a small message.
+    ///
+    /// # Arguments
+    /// *  `seed` - The seed of the maze.
+    fn message(seed: u64) -> messages::Message {
+        messages::Message::new(
+            "test",
+            "A",
+            maze::Shape::Hex,
+            initialize::LFSR::new(seed),
+            &xid::Key::new(b"test"),
+        )
+    }
+
+    /// Creates a cache over an in-memory store holding a message.
+    fn stores() -> (sync::Arc<dyn Store>, sync::Arc<CachedStore>) {
+        let store: sync::Arc<dyn Store> =
+            sync::Arc::new(MemoryStore::new(time::Duration::from_secs(60)));
+        store
+            .put_message(
+                &message(1),
+                &Options {
+                    owner: Some("owner".into()),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let cache =
+            CachedStore::new(store.clone(), 16, time::Duration::from_secs(60))
+                .unwrap();
+        (store, cache)
+    }
+
+    #[test]
+    fn caches_until_invalidated() {
+        let (store, cache) = stores();
+        let before = cache.get("test", None).unwrap().unwrap();
+
+        // Changes not made through the cache are not seen
+        store.replace_message(&message(2), "owner").unwrap();
+        assert_eq!(cache.get("test", None).unwrap().unwrap().see, before.see);
+
+        cache.invalidate(Some("test"));
+        let after = cache.get("test", None).unwrap().unwrap();
+        assert_ne!(after.see, before.see);
+        assert_eq!(after.see, store.get("test", None).unwrap().unwrap().see);
+    }
+
+    #[test]
+    fn writes_invalidate() {
+        let (store, cache) = stores();
+        cache.get("test", None).unwrap().unwrap();
+
+        cache.replace_message(&message(2), "owner").unwrap();
+        assert_eq!(
+            cache.get("test", None).unwrap().unwrap().see,
+            store.get("test", None).unwrap().unwrap().see,
+        );
+
+        cache.delete("test", "owner").unwrap();
+        assert!(cache.get("test", None).unwrap().is_none());
+        assert!(!cache.exists("test").unwrap());
+    }
+
+    #[test]
+    fn stale_reads_are_not_cached() {
+        let (_, cache) = stores();
+        let generation = cache.generation().unwrap();
+        cache.invalidate(Some("test"));
+
+        let mut cached = false;
+        cache
+            .cache("test", generation, || {
+                cached = true;
+                Ok(())
+            })
+            .unwrap();
+        assert!(!cached);
+        cache
+            .cache("other", generation, || {
+                cached = true;
+                Ok(())
+            })
+            .unwrap();
+        assert!(cached);
+    }
+
+    #[test]
+    fn generations_track_invalidations() {
+        let ttl = time::Duration::from_secs(60);
+        let mut generations = Generations::default();
+        generations.invalidate(Some("a"), ttl);
+        generations.invalidate(Some("b"), ttl);
+        assert!(generations.is_stale("a", 0));
+        assert!(!generations.is_stale("a", 1));
+        assert!(generations.is_stale("b", 1));
+        assert!(!generations.is_stale("c", 0));
+
+        generations.invalidate(None, ttl);
+        assert!(generations.is_stale("c", 2));
+        assert!(!generations.is_stale("c", 3));
+    }
+
+    #[test]
+    fn generations_forget_old_invalidations() {
+        let ttl = time::Duration::from_millis(0);
+        let mut generations = Generations::default();
+        generations.invalidate(Some("a"), ttl);
+        generations.invalidate(Some("a"), ttl);
+        generations.invalidate(Some("b"), ttl);
+        assert_eq!(generations.messages.len(), 1);
+        assert_eq!(generations.invalidations.len(), 1);
+        assert!(generations.is_stale("b", 2));
+        assert!(!generations.is_stale("a", 0));
+    }
+}
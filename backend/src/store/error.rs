@@ -17,6 +17,9 @@ pub enum Error {
     /// A message exists.
     Exists,
 
+    /// A message does not exist.
+    Missing,
+
     /// An error occurred while writing.
     ReadError,
 
@@ -55,7 +58,23 @@ impl From<r2d2::Error> for Error {
 }
 
 impl From<redis::RedisError> for Error {
-    fn from(_source: redis::RedisError) -> Self {
-        Self::Connection
+    fn from(source: redis::RedisError) -> Self {
+        // Only failures to reach the server are reported as such; anything
+        // else, like a reply of the wrong type, is a failed read
+        if source.is_io_error()
+            || source.is_connection_refusal()
+            || source.is_connection_dropped()
+            || source.is_timeout()
+        {
+            Self::Connection
+        } else {
+            Self::ReadError
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(_source: rusqlite::Error) -> Self {
+        Self::InternalError
     }
 }
@@ -0,0 +1,407 @@
+use std::collections::HashMap;
+use std::sync;
+use std::time;
+
+use futures::channel::oneshot;
+use futures::executor;
+use futures::future::{FutureExt, Shared};
+
+use crate::messages;
+use crate::messages::generator;
+use crate::messages::xid;
+
+use super::lru::Lru;
+use super::{Error, Options, Store, Watcher};
+
+/// A regeneration in progress, shared by all readers waiting for it.
+type Pending = Shared<oneshot::Receiver<sync::Arc<messages::Message>>>;
+
+/// A store persisting only the recipes of messages.
+///
+/// Messages are regenerated from their recipes when first read, and the most
+/// recently read ones are kept in process memory. The recipe is read from
+/// the underlying store on every access and the cache is keyed on it, so a
+/// message that has expired, or been replaced by another with the same name,
+/// is never served from the cache.
+///
+/// Regeneration runs on the generator pool, and readers of a message that is
+/// already being regenerated wait for that regeneration instead of starting
+/// another one.
+///
+/// Only messages stored by recipe alone are regenerated. Messages stored in
+/// full, for instance before lazy storage was enabled, keep the rooms they
+/// were stored with, and are read from the underlying store.
+pub struct LazyStore {
+    /// The underlying store.
+    store: sync::Arc<dyn Store>,
+
+    /// The regenerated messages, keyed on serialised recipe.
+    cache: sync::Mutex<Lru<Vec<u8>, sync::Arc<messages::Message>>>,
+
+    /// The regenerations in progress, keyed on serialised recipe.
+    pending: sync::Mutex<HashMap<Vec<u8>, Pending>>,
+
+    /// The pool of threads generating messages.
+    generator: sync::Arc<generator::Generator>,
+}
+
+impl LazyStore {
+    /// Creates a new store.
+    ///
+    /// # Arguments
+    /// *  `store` - The underlying store.
+    /// *  `cache_size` - The maximum number of regenerated messages kept in
+    ///    memory.
+    /// *  `generator` - The pool of threads regenerating messages.
+    pub fn new(
+        store: sync::Arc<dyn Store>,
+        cache_size: usize,
+        generator: sync::Arc<generator::Generator>,
+    ) -> Self {
+        Self {
+            store,
+            cache: sync::Mutex::new(Lru::new(cache_size)),
+            pending: sync::Mutex::new(HashMap::new()),
+            generator,
+        }
+    }
+
+    /// Reads a message, regenerating it from its recipe if it is not cached.
+    ///
+    /// This blocks until the message has been regenerated.
+    ///
+    /// # Arguments
+    /// *  `message_name` - The name of the message.
+    fn message(
+        &self,
+        message_name: &str,
+    ) -> Result<Option<sync::Arc<messages::Message>>, Error> {
+        let recipe = match self.store.get_recipe(message_name)? {
+            Some(recipe) => recipe,
+            None => return Ok(None),
+        };
+        let key = rmp_serde::to_vec(&recipe).map_err(|_| Error::ReadError)?;
+
+        let cached = self
+            .cache
+            .lock()
+            .map_err(|_| Error::InternalError)?
+            .get(&key);
+        if let Some(message) = cached {
+            return Ok(Some(message));
+        }
+
+        // Only the first reader starts a regeneration
+        let (pending, sender) = {
+            let mut pending =
+                self.pending.lock().map_err(|_| Error::InternalError)?;
+            match pending.get(&key) {
+                Some(regeneration) => (regeneration.clone(), None),
+                None => {
+                    let (tx, rx) = oneshot::channel();
+                    let regeneration = rx.shared();
+                    pending.insert(key.clone(), regeneration.clone());
+                    (regeneration, Some(tx))
+                }
+            }
+        };
+        if let Some(tx) = sender {
+            let generator_key = self.generator.key();
+            let queued = self.generator.queue(move || {
+                let _ = tx.send(sync::Arc::new(
+                    messages::Message::from_recipe(&recipe, &generator_key),
+                ));
+            });
+            if queued.is_err() {
+                self.finish(&key, None)?;
+                return Err(Error::InternalError);
+            }
+        }
+
+        // A regeneration that panicked leaves its channel closed
+        let message =
+            executor::block_on(pending).map_err(|_| Error::InternalError);
+        self.finish(&key, message.as_ref().ok().cloned())?;
+        message.map(Some)
+    }
+
+    /// Completes a regeneration by caching its result, if any, and
+    /// forgetting that it is in progress.
+    ///
+    /// # Arguments
+    /// *  `key` - The serialised recipe.
+    /// *  `message` - The regenerated message.
+    fn finish(
+        &self,
+        key: &[u8],
+        message: Option<sync::Arc<messages::Message>>,
+    ) -> Result<(), Error> {
+        if let Some(message) = message {
+            self.cache
+                .lock()
+                .map_err(|_| Error::InternalError)?
+                .insert(key.to_vec(), message);
+        }
+        self.pending
+            .lock()
+            .map_err(|_| Error::InternalError)?
+            .remove(key);
+        Ok(())
+    }
+}
+
+impl Store for LazyStore {
+    fn get(
+        &self,
+        message_name: &str,
+        id: Option<xid::Identifier>,
+    ) -> Result<Option<messages::Room>, Error> {
+        // Messages stored by recipe have no stored entrance
+        match (self.store.get(message_name, None)?, id) {
+            (Some(entrance), None) => Ok(Some(entrance)),
+            (Some(_), Some(_)) => self.store.get(message_name, id),
+            (None, _) => {
+                Ok(self.message(message_name)?.and_then(|m| m.room(id)))
+            }
+        }
+    }
+
+    fn get_rooms(
+        &self,
+        message_name: &str,
+        ids: &[xid::Identifier],
+    ) -> Result<Vec<messages::Room>, Error> {
+        if self.store.get(message_name, None)?.is_some() {
+            return self.store.get_rooms(message_name, ids);
+        }
+
+        Ok(self
+            .message(message_name)?
+            .map(|message| {
+                ids.iter()
+                    .filter_map(|&id| message.room(Some(id)))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    fn exists(&self, message_name: &str) -> Result<bool, Error> {
+        self.store.exists(message_name)
+    }
+
+    fn expires_in(
+        &self,
+        message_name: &str,
+    ) -> Result<Option<time::Duration>, Error> {
+        self.store.expires_in(message_name)
+    }
+
+    fn touch(
+        &self,
+        message_name: &str,
+        max_lifetime: time::Duration,
+    ) -> Result<(), Error> {
+        self.store.touch(message_name, max_lifetime)
+    }
+
+    fn put_message(
+        &self,
+        message: &messages::Message,
+        options: &Options,
+    ) -> Result<(), Error> {
+        self.store.put_recipe(message, options)
+    }
+
+    fn get_recipe(
+        &self,
+        message_name: &str,
+    ) -> Result<Option<messages::Recipe>, Error> {
+        self.store.get_recipe(message_name)
+    }
+
+    fn put_recipe(
+        &self,
+        message: &messages::Message,
+        options: &Options,
+    ) -> Result<(), Error> {
+        self.store.put_recipe(message, options)
+    }
+
+    fn replace_message(
+        &self,
+        message: &messages::Message,
+        owner: &str,
+    ) -> Result<(), Error> {
+        self.store.replace_recipe(message, owner)
+    }
+
+    fn replace_recipe(
+        &self,
+        message: &messages::Message,
+        owner: &str,
+    ) -> Result<(), Error> {
+        self.store.replace_recipe(message, owner)
+    }
+
+    fn get_owner(&self, message_name: &str) -> Result<Option<String>, Error> {
+        self.store.get_owner(message_name)
+    }
+
+    fn is_private(&self, message_name: &str) -> Result<bool, Error> {
+        self.store.is_private(message_name)
+    }
+
+    fn delete(&self, message_name: &str, owner: &str) -> Result<bool, Error> {
+        self.store.delete(message_name, owner)
+    }
+
+    fn get_summary(
+        &self,
+        message_name: &str,
+    ) -> Result<Option<(messages::Summary, time::SystemTime)>, Error> {
+        self.store.get_summary(message_name)
+    }
+
+    fn get_exploration(
+        &self,
+        token: &str,
+    ) -> Result<Option<messages::Exploration>, Error> {
+        self.store.get_exploration(token)
+    }
+
+    fn get_visited(&self, token: &str) -> Result<Vec<xid::Identifier>, Error> {
+        self.store.get_visited(token)
+    }
+
+    fn visit(
+        &self,
+        token: &str,
+        message_name: &str,
+        sequence: u64,
+        id: xid::Identifier,
+    ) -> Result<bool, Error> {
+        self.store.visit(token, message_name, sequence, id)
+    }
+
+    fn put_nonce(&self, nonce: &str, ttl: time::Duration) -> Result<(), Error> {
+        self.store.put_nonce(nonce, ttl)
+    }
+
+    fn take_nonce(&self, nonce: &str) -> Result<bool, Error> {
+        self.store.take_nonce(nonce)
+    }
+
+    fn watch(&self, watcher: Watcher) -> Result<(), Error> {
+        self.store.watch(watcher)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::thread;
+
+    use maze::initialize;
+
+    use super::*;
+    use crate::store::MemoryStore;
+
+    /// Generates a small message.
+    ///
+    /// # Arguments
+    /// *  `key` - The key from which room identifiers are derived.
+    fn message(key: &xid::Key) -> messages::Message {
+        messages::Message::new(
+            "test",
+            "A",
+            maze::Shape::Hex,
+            initialize::LFSR::new(12345),
+            key,
+        )
+    }
+
+    /// Creates a lazy store over an in-memory store.
+    ///
+    /// # Arguments
+    /// *  `generator` - The pool of threads regenerating messages.
+    fn stores(
+        generator: sync::Arc<generator::Generator>,
+    ) -> (sync::Arc<dyn Store>, sync::Arc<LazyStore>) {
+        let store: sync::Arc<dyn Store> =
+            sync::Arc::new(MemoryStore::new(time::Duration::from_secs(60)));
+        let lazy = sync::Arc::new(LazyStore::new(store.clone(), 4, generator));
+        (store, lazy)
+    }
+
+    #[test]
+    fn regenerates_recipes() {
+        let key = xid::Key::new(b"test");
+        let generator =
+            sync::Arc::new(generator::Generator::new(1, 4, key.clone()));
+        let (store, lazy) = stores(generator);
+        let message = message(&key);
+        lazy.put_message(&message, &Default::default()).unwrap();
+
+        assert!(store.get("test", None).unwrap().is_none());
+        let entrance = lazy.get("test", None).unwrap().unwrap();
+        assert_eq!(entrance.see, message.room(None).unwrap().see);
+        assert_eq!(
+            lazy.get_rooms("test", &entrance.see).unwrap().len(),
+            entrance.see.len()
+        );
+    }
+
+    #[test]
+    fn reads_stored_messages() {
+        // The stored rooms were derived with another key than the one used
+        // for regeneration
+        let generator = sync::Arc::new(generator::Generator::new(
+            1,
+            4,
+            xid::Key::new(b"other"),
+        ));
+        let (store, lazy) = stores(generator);
+        let message = message(&xid::Key::new(b"test"));
+        store.put_message(&message, &Default::default()).unwrap();
+
+        let entrance = lazy.get("test", None).unwrap().unwrap();
+        assert_eq!(entrance.see, message.room(None).unwrap().see);
+        let room = lazy.get("test", Some(entrance.see[0])).unwrap().unwrap();
+        assert_eq!(room.xid, entrance.see[0]);
+        assert_eq!(
+            lazy.get_rooms("test", &entrance.see).unwrap().len(),
+            entrance.see.len(),
+        );
+    }
+
+    #[test]
+    fn regenerates_once() {
+        let key = xid::Key::new(b"test");
+        let generator =
+            sync::Arc::new(generator::Generator::new(1, 8, key.clone()));
+        let (_, lazy) = stores(generator.clone());
+        lazy.put_message(&message(&key), &Default::default())
+            .unwrap();
+
+        // Keep the only worker busy until all readers are waiting
+        let (release, blocked) = mpsc::channel::<()>();
+        generator.queue(move || blocked.recv().unwrap()).unwrap();
+        let readers = (0..4)
+            .map(|_| {
+                let lazy = lazy.clone();
+                thread::spawn(move || lazy.message("test").unwrap().unwrap())
+            })
+            .collect::<Vec<_>>();
+        thread::sleep(time::Duration::from_millis(100));
+        release.send(()).unwrap();
+
+        let messages = readers
+            .into_iter()
+            .map(|reader| reader.join().unwrap())
+            .collect::<Vec<_>>();
+        assert!(messages
+            .iter()
+            .all(|message| sync::Arc::ptr_eq(message, &messages[0])));
+        assert!(lazy.pending.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,265 @@
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// A bounded map evicting the least recently used entry once full.
+///
+/// Entries are kept in a doubly linked list ordered by use, so reading,
+/// adding and evicting entries take constant time.
+pub struct Lru<K, V> {
+    /// The positions of the entries in `nodes`, keyed on entry key.
+    index: HashMap<K, usize>,
+
+    /// The entries; removed entries leave an empty slot.
+    nodes: Vec<Option<Node<K, V>>>,
+
+    /// The empty slots in `nodes`.
+    free: Vec<usize>,
+
+    /// The most recently used entry.
+    head: Option<usize>,
+
+    /// The least recently used entry.
+    tail: Option<usize>,
+
+    /// The maximum number of entries.
+    capacity: usize,
+}
+
+/// An entry in the list of entries.
+struct Node<K, V> {
+    /// The key of the entry.
+    key: K,
+
+    /// The value of the entry.
+    value: V,
+
+    /// The more recently used neighbour.
+    prev: Option<usize>,
+
+    /// The less recently used neighbour.
+    next: Option<usize>,
+}
+
+impl<K, V> Lru<K, V>
+where
+    K: Clone + Eq + Hash,
+    V: Clone,
+{
+    /// Creates an empty cache.
+    ///
+    /// # Arguments
+    /// *  `capacity` - The maximum number of entries; a capacity of 0
+    ///    disables the cache.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            index: HashMap::new(),
+            nodes: Vec::new(),
+            free: Vec::new(),
+            head: None,
+            tail: None,
+            capacity,
+        }
+    }
+
+    /// Reads an entry and marks it as most recently used.
+    ///
+    /// # Arguments
+    /// *  `key` - The key of the entry.
+    pub fn get<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let i = *self.index.get(key)?;
+        self.unlink(i);
+        self.push_front(i);
+        Some(self.node_mut(i).value.clone())
+    }
+
+    /// Adds an entry, evicting the least recently used one if the cache is
+    /// full.
+    ///
+    /// # Arguments
+    /// *  `key` - The key of the entry.
+    /// *  `value` - The value of the entry.
+    pub fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some(&i) = self.index.get(&key) {
+            self.node_mut(i).value = value;
+            self.unlink(i);
+            self.push_front(i);
+            return;
+        }
+        if self.index.len() >= self.capacity {
+            if let Some(oldest) = self.tail {
+                self.remove_at(oldest);
+            }
+        }
+
+        let node = Node {
+            key: key.clone(),
+            value,
+            prev: None,
+            next: None,
+        };
+        let i = match self.free.pop() {
+            Some(i) => {
+                self.nodes[i] = Some(node);
+                i
+            }
+            None => {
+                self.nodes.push(Some(node));
+                self.nodes.len() - 1
+            }
+        };
+        self.index.insert(key, i);
+        self.push_front(i);
+    }
+
+    /// Removes an entry.
+    ///
+    /// # Arguments
+    /// *  `key` - The key of the entry.
+    pub fn remove<Q>(&mut self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        if let Some(&i) = self.index.get(key) {
+            self.remove_at(i);
+        }
+    }
+
+    /// Removes all entries.
+    pub fn clear(&mut self) {
+        self.index.clear();
+        self.nodes.clear();
+        self.free.clear();
+        self.head = None;
+        self.tail = None;
+    }
+
+    /// Accesses an entry in the list.
+    ///
+    /// # Arguments
+    /// *  `i` - The position of the entry.
+    fn node_mut(&mut self, i: usize) -> &mut Node<K, V> {
+        self.nodes[i].as_mut().expect("listed entries are present")
+    }
+
+    /// Removes an entry from the list, leaving its slot in place.
+    ///
+    /// # Arguments
+    /// *  `i` - The position of the entry.
+    fn unlink(&mut self, i: usize) {
+        let (prev, next) = {
+            let node = self.node_mut(i);
+            (node.prev, node.next)
+        };
+        match prev {
+            Some(prev) => self.node_mut(prev).next = next,
+            None => self.head = next,
+        }
+        match next {
+            Some(next) => self.node_mut(next).prev = prev,
+            None => self.tail = prev,
+        }
+    }
+
+    /// Adds an entry to the front of the list as the most recently used one.
+    ///
+    /// # Arguments
+    /// *  `i` - The position of the entry.
+    fn push_front(&mut self, i: usize) {
+        let head = self.head;
+        {
+            let node = self.node_mut(i);
+            node.prev = None;
+            node.next = head;
+        }
+        match head {
+            Some(head) => self.node_mut(head).prev = Some(i),
+            None => self.tail = Some(i),
+        }
+        self.head = Some(i);
+    }
+
+    /// Removes an entry and frees its slot.
+    ///
+    /// # Arguments
+    /// *  `i` - The position of the entry.
+    fn remove_at(&mut self, i: usize) {
+        self.unlink(i);
+        if let Some(node) = self.nodes[i].take() {
+            self.index.remove(&node.key);
+            self.free.push(i);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut lru = Lru::new(2);
+        lru.insert("a", 1);
+        lru.insert("b", 2);
+        assert_eq!(lru.get("a"), Some(1));
+        lru.insert("c", 3);
+        assert_eq!(lru.get("b"), None);
+        assert_eq!(lru.get("a"), Some(1));
+        assert_eq!(lru.get("c"), Some(3));
+    }
+
+    #[test]
+    fn insert_replaces_and_marks_used() {
+        let mut lru = Lru::new(2);
+        lru.insert("a", 1);
+        lru.insert("b", 2);
+        lru.insert("a", 3);
+        lru.insert("c", 4);
+        assert_eq!(lru.get("a"), Some(3));
+        assert_eq!(lru.get("b"), None);
+        assert_eq!(lru.get("c"), Some(4));
+    }
+
+    #[test]
+    fn zero_capacity_stores_nothing() {
+        let mut lru = Lru::new(0);
+        lru.insert("a", 1);
+        assert_eq!(lru.get("a"), None);
+    }
+
+    #[test]
+    fn remove_frees_slots() {
+        let mut lru = Lru::new(3);
+        lru.insert("a", 1);
+        lru.insert("b", 2);
+        lru.insert("c", 3);
+        lru.remove("b");
+        assert_eq!(lru.get("b"), None);
+
+        // The freed slot is reused, and eviction still follows use
+        lru.insert("d", 4);
+        lru.insert("e", 5);
+        assert_eq!(lru.get("a"), None);
+        assert_eq!(lru.get("c"), Some(3));
+        assert_eq!(lru.get("d"), Some(4));
+        assert_eq!(lru.get("e"), Some(5));
+    }
+
+    #[test]
+    fn clear_removes_all() {
+        let mut lru = Lru::new(2);
+        lru.insert("a", 1);
+        lru.clear();
+        assert_eq!(lru.get("a"), None);
+        lru.insert("b", 2);
+        assert_eq!(lru.get("b"), Some(2));
+    }
+}
@@ -0,0 +1,617 @@
+use std::collections::{HashMap, HashSet};
+use std::sync;
+use std::time;
+
+use crate::messages;
+use crate::messages::xid;
+
+use super::{Error, Options, Store};
+
+/// A message held in memory.
+struct Entry {
+    /// The time at which this message was created.
+    created: time::Instant,
+
+    /// The time at which this message expires.
+    expires: time::Instant,
+
+    /// The time-to-live of this message.
+    ttl: time::Duration,
+
+    /// The entrance room, unless the message is stored by recipe.
+    entrance: Option<messages::Room>,
+
+    /// All rooms of the message.
+    rooms: HashMap<xid::Identifier, messages::Room>,
+
+    /// The recipe of the message.
+    recipe: messages::Recipe,
+
+    /// The summary of the message.
+    summary: messages::Summary,
+
+    /// The hash of the management token of the message.
+    owner: Option<String>,
+
+    /// Whether room identifiers are encrypted per session.
+    private: bool,
+}
+
+/// A persisted exploration held in memory.
+struct ExplorationEntry {
+    /// The time at which this exploration expires.
+    expires: time::Instant,
+
+    /// The current position.
+    exploration: messages::Exploration,
+
+    /// The visited rooms, in the order they were first visited.
+    visited: Vec<xid::Identifier>,
+
+    /// The visited rooms, for fast lookup.
+    seen: HashSet<xid::Identifier>,
+}
+
+/// An in-process store.
+///
+/// Messages are lost when the process exits, so this store is intended for
+/// local development and testing.
+pub struct MemoryStore {
+    /// The stored messages.
+    entries: sync::Mutex<HashMap<String, Entry>>,
+
+    /// The persisted explorations, and the times at which they expire.
+    explorations: sync::Mutex<HashMap<String, ExplorationEntry>>,
+
+    /// The unused nonces, and the times at which they expire.
+    nonces: sync::Mutex<HashMap<String, time::Instant>>,
+
+    /// The TTL for records.
+    ttl: time::Duration,
+}
+
+impl MemoryStore {
+    /// Creates a new store.
+    ///
+    /// # Arguments
+    /// *  `ttl` - The time-to-live for records.
+    pub fn new(ttl: time::Duration) -> Self {
+        Self {
+            entries: sync::Mutex::new(HashMap::new()),
+            explorations: sync::Mutex::new(HashMap::new()),
+            nonces: sync::Mutex::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    /// Locks the stored messages.
+    fn entries(
+        &self,
+    ) -> Result<sync::MutexGuard<'_, HashMap<String, Entry>>, Error> {
+        self.entries.lock().map_err(|_| Error::InternalError)
+    }
+
+    /// Adds a message unless one with the same name exists.
+    ///
+    /// # Arguments
+    /// *  `message_name` - The name of the message.
+    /// *  `entry` - The message.
+    fn insert(&self, message_name: &str, entry: Entry) -> Result<(), Error> {
+        // Expired messages are purged lazily
+        let now = time::Instant::now();
+        let mut entries = self.entries()?;
+        entries.retain(|_, entry| entry.expires > now);
+
+        if entries.contains_key(message_name) {
+            Err(Error::Exists)
+        } else {
+            entries.insert(message_name.into(), entry);
+            Ok(())
+        }
+    }
+
+    /// Modifies an unexpired message.
+    ///
+    /// This fails with [`Error::Missing`] if no such message with the given
+    /// owner exists.
+    ///
+    /// # Arguments
+    /// *  `message_name` - The name of the message.
+    /// *  `owner` - The hash of the token required to manage the message.
+    /// *  `f` - The function modifying the message.
+    fn modify<F>(
+        &self,
+        message_name: &str,
+        owner: &str,
+        f: F,
+    ) -> Result<(), Error>
+    where
+        F: FnOnce(&mut Entry),
+    {
+        let now = time::Instant::now();
+        match self.entries()?.get_mut(message_name).filter(|entry| {
+            entry.expires > now && entry.owner.as_deref() == Some(owner)
+        }) {
+            Some(entry) => {
+                f(entry);
+                Ok(())
+            }
+            None => Err(Error::Missing),
+        }
+    }
+
+    /// Locks the persisted explorations.
+    fn explorations(
+        &self,
+    ) -> Result<sync::MutexGuard<'_, HashMap<String, ExplorationEntry>>, Error>
+    {
+        self.explorations.lock().map_err(|_| Error::InternalError)
+    }
+
+    /// Locks the unused nonces.
+    fn nonces(
+        &self,
+    ) -> Result<sync::MutexGuard<'_, HashMap<String, time::Instant>>, Error>
+    {
+        self.nonces.lock().map_err(|_| Error::InternalError)
+    }
+}
+
+impl Store for MemoryStore {
+    fn get(
+        &self,
+        message_name: &str,
+        id: Option<xid::Identifier>,
+    ) -> Result<Option<messages::Room>, Error> {
+        let now = time::Instant::now();
+        Ok(self
+            .entries()?
+            .get(message_name)
+            .filter(|entry| entry.expires > now)
+            .and_then(|entry| match id {
+                Some(id) => entry.rooms.get(&id),
+                None => entry.entrance.as_ref(),
+            })
+            .cloned())
+    }
+
+    fn get_rooms(
+        &self,
+        message_name: &str,
+        ids: &[xid::Identifier],
+    ) -> Result<Vec<messages::Room>, Error> {
+        let now = time::Instant::now();
+        Ok(self
+            .entries()?
+            .get(message_name)
+            .filter(|entry| entry.expires > now)
+            .map(|entry| {
+                ids.iter()
+                    .filter_map(|id| entry.rooms.get(id).cloned())
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    fn exists(&self, message_name: &str) -> Result<bool, Error> {
+        let now = time::Instant::now();
+        Ok(self
+            .entries()?
+            .get(message_name)
+            .map_or(false, |entry| entry.expires > now))
+    }
+
+    fn expires_in(
+        &self,
+        message_name: &str,
+    ) -> Result<Option<time::Duration>, Error> {
+        let now = time::Instant::now();
+        Ok(self
+            .entries()?
+            .get(message_name)
+            .filter(|entry| entry.expires > now)
+            .map(|entry| entry.expires - now))
+    }
+
+    fn touch(
+        &self,
+        message_name: &str,
+        max_lifetime: time::Duration,
+    ) -> Result<(), Error> {
+        let now = time::Instant::now();
+        if let Some(entry) = self
+            .entries()?
+            .get_mut(message_name)
+            .filter(|entry| entry.expires > now)
+        {
+            entry.expires = entry
+                .expires
+                .max((now + entry.ttl).min(entry.created + max_lifetime));
+        }
+        Ok(())
+    }
+
+    fn put_message(
+        &self,
+        message: &messages::Message,
+        options: &Options,
+    ) -> Result<(), Error> {
+        let now = time::Instant::now();
+        let ttl = options.ttl.unwrap_or(self.ttl);
+        let entrance = message
+            .describe((0isize, 0isize).into())
+            .ok_or(Error::InternalError)?;
+        let rooms = message.rooms().map(|room| (room.xid, room)).collect();
+
+        self.insert(
+            message.name(),
+            Entry {
+                created: now,
+                expires: now + ttl,
+                ttl,
+                entrance: Some(entrance),
+                rooms,
+                recipe: message.recipe().clone(),
+                summary: message.summary(),
+                owner: options.owner.clone(),
+                private: options.private,
+            },
+        )
+    }
+
+    fn get_recipe(
+        &self,
+        message_name: &str,
+    ) -> Result<Option<messages::Recipe>, Error> {
+        let now = time::Instant::now();
+        Ok(self
+            .entries()?
+            .get(message_name)
+            .filter(|entry| entry.expires > now)
+            .map(|entry| entry.recipe.clone()))
+    }
+
+    fn put_recipe(
+        &self,
+        message: &messages::Message,
+        options: &Options,
+    ) -> Result<(), Error> {
+        let now = time::Instant::now();
+        let ttl = options.ttl.unwrap_or(self.ttl);
+        self.insert(
+            message.name(),
+            Entry {
+                created: now,
+                expires: now + ttl,
+                ttl,
+                entrance: None,
+                rooms: HashMap::new(),
+                recipe: message.recipe().clone(),
+                summary: message.summary(),
+                owner: options.owner.clone(),
+                private: options.private,
+            },
+        )
+    }
+
+    fn replace_message(
+        &self,
+        message: &messages::Message,
+        owner: &str,
+    ) -> Result<(), Error> {
+        let entrance = message
+            .describe((0isize, 0isize).into())
+            .ok_or(Error::InternalError)?;
+        let rooms = message.rooms().map(|room| (room.xid, room)).collect();
+
+        self.modify(message.name(), owner, |entry| {
+            entry.entrance = Some(entrance);
+            entry.rooms = rooms;
+            entry.recipe = message.recipe().clone();
+            entry.summary = message.summary();
+        })
+    }
+
+    fn replace_recipe(
+        &self,
+        message: &messages::Message,
+        owner: &str,
+    ) -> Result<(), Error> {
+        self.modify(message.name(), owner, |entry| {
+            entry.entrance = None;
+            entry.rooms = HashMap::new();
+            entry.recipe = message.recipe().clone();
+            entry.summary = message.summary();
+        })
+    }
+
+    fn get_owner(&self, message_name: &str) -> Result<Option<String>, Error> {
+        let now = time::Instant::now();
+        Ok(self
+            .entries()?
+            .get(message_name)
+            .filter(|entry| entry.expires > now)
+            .and_then(|entry| entry.owner.clone()))
+    }
+
+    fn is_private(&self, message_name: &str) -> Result<bool, Error> {
+        let now = time::Instant::now();
+        Ok(self
+            .entries()?
+            .get(message_name)
+            .map_or(false, |entry| entry.expires > now && entry.private))
+    }
+
+    fn delete(&self, message_name: &str, owner: &str) -> Result<bool, Error> {
+        let now = time::Instant::now();
+        let mut entries = self.entries()?;
+        let owned = entries.get(message_name).map_or(false, |entry| {
+            entry.expires > now && entry.owner.as_deref() == Some(owner)
+        });
+        if owned {
+            entries.remove(message_name);
+        }
+        Ok(owned)
+    }
+
+    fn get_summary(
+        &self,
+        message_name: &str,
+    ) -> Result<Option<(messages::Summary, time::SystemTime)>, Error> {
+        let now = time::Instant::now();
+        Ok(self
+            .entries()?
+            .get(message_name)
+            .filter(|entry| entry.expires > now)
+            .map(|entry| {
+                (
+                    entry.summary.clone(),
+                    time::SystemTime::now() - (now - entry.created),
+                )
+            }))
+    }
+
+    fn get_exploration(
+        &self,
+        token: &str,
+    ) -> Result<Option<messages::Exploration>, Error> {
+        let now = time::Instant::now();
+        Ok(self
+            .explorations()?
+            .get(token)
+            .filter(|entry| entry.expires > now)
+            .map(|entry| entry.exploration.clone()))
+    }
+
+    fn get_visited(&self, token: &str) -> Result<Vec<xid::Identifier>, Error> {
+        let now = time::Instant::now();
+        Ok(self
+            .explorations()?
+            .get(token)
+            .filter(|entry| entry.expires > now)
+            .map(|entry| entry.visited.clone())
+            .unwrap_or_default())
+    }
+
+    fn visit(
+        &self,
+        token: &str,
+        message_name: &str,
+        sequence: u64,
+        id: xid::Identifier,
+    ) -> Result<bool, Error> {
+        // Expired explorations are purged lazily
+        let now = time::Instant::now();
+        let mut explorations = self.explorations()?;
+        explorations.retain(|_, entry| entry.expires > now);
+
+        let moves = explorations
+            .get(token)
+            .filter(|entry| entry.exploration.message == message_name)
+            .map_or(0, |entry| entry.exploration.sequence);
+        if moves != sequence {
+            return Ok(false);
+        }
+
+        let entry = explorations.entry(token.into()).or_insert_with(|| {
+            ExplorationEntry {
+                expires: now,
+                exploration: messages::Exploration {
+                    message: message_name.into(),
+                    current: id,
+                    sequence: 0,
+                },
+                visited: Vec::new(),
+                seen: HashSet::new(),
+            }
+        });
+        if entry.exploration.message != message_name {
+            entry.exploration.message = message_name.into();
+            entry.visited.clear();
+            entry.seen.clear();
+        }
+        entry.expires = now + self.ttl;
+        entry.exploration.current = id;
+        entry.exploration.sequence = sequence + 1;
+        if entry.seen.insert(id) {
+            entry.visited.push(id);
+        }
+        Ok(true)
+    }
+
+    fn put_nonce(&self, nonce: &str, ttl: time::Duration) -> Result<(), Error> {
+        // Expired nonces are purged lazily
+        let now = time::Instant::now();
+        let mut nonces = self.nonces()?;
+        nonces.retain(|_, expires| *expires > now);
+
+        nonces.insert(nonce.into(), now + ttl);
+        Ok(())
+    }
+
+    fn take_nonce(&self, nonce: &str) -> Result<bool, Error> {
+        let now = time::Instant::now();
+        Ok(self
+            .nonces()?
+            .remove(nonce)
+            .map_or(false, |expires| expires > now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use maze::initialize;
+
+    use super::*;
+
+    /// Generates a small message.
+    ///
+    /// # Arguments
+    /// *  `name` - The name of the message.
+    fn message(name: &str) -> messages::Message {
+        messages::Message::new(
+            name,
+            "A",
+            maze::Shape::Hex,
+            initialize::LFSR::new(12345),
+            &xid::Key::new(b"test"),
+        )
+    }
+
+    /// Settings with a time-to-live.
+    ///
+    /// # Arguments
+    /// *  `ttl` - The time-to-live of the message.
+    fn options(ttl: time::Duration) -> Options {
+        Options {
+            ttl: Some(ttl),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn put_message() {
+        let store = MemoryStore::new(time::Duration::from_secs(60));
+        let message = message("test");
+        store.put_message(&message, &Default::default()).unwrap();
+
+        assert!(store.exists("test").unwrap());
+        assert!(!store.exists("other").unwrap());
+        let entrance = store.get("test", None).unwrap().unwrap();
+        assert_eq!(
+            store
+                .get("test", Some(entrance.see[0]))
+                .unwrap()
+                .unwrap()
+                .xid,
+            entrance.see[0],
+        );
+        assert!(store.get_recipe("test").unwrap().is_some());
+        assert!(matches!(
+            store.put_message(&message, &Default::default()),
+            Err(Error::Exists)
+        ));
+    }
+
+    #[test]
+    fn get_rooms_skips_unknown() {
+        let store = MemoryStore::new(time::Duration::from_secs(60));
+        store
+            .put_message(&message("test"), &Default::default())
+            .unwrap();
+
+        let entrance = store.get("test", None).unwrap().unwrap();
+        let ids = vec![entrance.see[0], xid::Identifier::default()];
+        let rooms = store.get_rooms("test", &ids).unwrap();
+        assert_eq!(rooms.len(), 1);
+        assert_eq!(rooms[0].xid, entrance.see[0]);
+        assert!(store.get_rooms("other", &ids).unwrap().is_empty());
+    }
+
+    #[test]
+    fn messages_expire() {
+        let store = MemoryStore::new(time::Duration::from_secs(60));
+        let ttl = time::Duration::from_millis(20);
+        store.put_message(&message("test"), &options(ttl)).unwrap();
+        assert!(store.exists("test").unwrap());
+
+        thread::sleep(ttl * 2);
+        assert!(!store.exists("test").unwrap());
+        assert!(store.get("test", None).unwrap().is_none());
+        assert!(store.expires_in("test").unwrap().is_none());
+
+        // The name of an expired message is free
+        store
+            .put_message(&message("test"), &Default::default())
+            .unwrap();
+    }
+
+    #[test]
+    fn delete_checks_owner() {
+        let store = MemoryStore::new(time::Duration::from_secs(60));
+        let options = Options {
+            owner: Some("owner".into()),
+            ..Default::default()
+        };
+        store.put_message(&message("test"), &options).unwrap();
+        store
+            .put_message(&message("unowned"), &Default::default())
+            .unwrap();
+
+        assert!(!store.delete("test", "other").unwrap());
+        assert!(store.exists("test").unwrap());
+        assert!(store.delete("test", "owner").unwrap());
+        assert!(!store.exists("test").unwrap());
+        assert!(!store.delete("test", "owner").unwrap());
+
+        // Messages without an owner cannot be removed
+        assert!(!store.delete("unowned", "owner").unwrap());
+        assert!(store.exists("unowned").unwrap());
+    }
+
+    #[test]
+    fn nonces_are_single_use() {
+        let store = MemoryStore::new(time::Duration::from_secs(60));
+        store
+            .put_nonce("nonce", time::Duration::from_secs(60))
+            .unwrap();
+
+        assert!(store.take_nonce("nonce").unwrap());
+        assert!(!store.take_nonce("nonce").unwrap());
+        assert!(!store.take_nonce("other").unwrap());
+    }
+
+    #[test]
+    fn nonces_expire() {
+        let store = MemoryStore::new(time::Duration::from_secs(60));
+        let ttl = time::Duration::from_millis(20);
+        store.put_nonce("nonce", ttl).unwrap();
+
+        thread::sleep(ttl * 2);
+        assert!(!store.take_nonce("nonce").unwrap());
+    }
+
+    #[test]
+    fn visits_are_sequenced() {
+        let store = MemoryStore::new(time::Duration::from_secs(60));
+        let (a, b) = (xid::Identifier::from(1), xid::Identifier::from(2));
+
+        assert!(store.visit("token", "test", 0, a).unwrap());
+        assert!(!store.visit("token", "test", 0, b).unwrap());
+        assert!(store.visit("token", "test", 1, b).unwrap());
+        assert!(store.visit("token", "test", 2, a).unwrap());
+
+        let exploration = store.get_exploration("token").unwrap().unwrap();
+        assert_eq!(exploration.message, "test");
+        assert_eq!(exploration.current, a);
+        assert_eq!(exploration.sequence, 3);
+        assert_eq!(store.get_visited("token").unwrap(), vec![a, b]);
+
+        // Exploring another message starts over
+        assert!(!store.visit("token", "other", 3, b).unwrap());
+        assert!(store.visit("token", "other", 0, b).unwrap());
+        assert_eq!(store.get_visited("token").unwrap(), vec![b]);
+    }
+}
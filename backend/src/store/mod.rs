@@ -1,144 +1,316 @@
 use std::time;
 
-use r2d2_redis;
-use r2d2_redis::r2d2;
-use r2d2_redis::redis;
-use r2d2_redis::redis::Commands;
-
 use crate::messages;
 use crate::messages::xid;
 
+mod cache;
+pub use self::cache::CachedStore;
+
 mod error;
 pub use self::error::Error;
 
-/// A distributed store.
-#[derive(Clone)]
-pub struct Store {
-    /// The connection pool.
-    pool: r2d2::Pool<r2d2_redis::RedisConnectionManager>,
+mod lazy;
+pub use self::lazy::LazyStore;
+
+mod lru;
+
+mod memory;
+pub use self::memory::MemoryStore;
+
+mod redis;
+pub use self::redis::RedisStore;
+
+mod sqlite;
+pub use self::sqlite::SqliteStore;
 
-    /// The TTL for records.
-    ttl: time::Duration,
+/// The settings of a message chosen at creation.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// The time-to-live of the message, or `None` to use the configured TTL.
+    pub ttl: Option<time::Duration>,
+
+    /// The hash of the token required to manage the message, or `None` if
+    /// the message cannot be managed.
+    pub owner: Option<String>,
+
+    /// Whether room identifiers are encrypted per session.
+    pub private: bool,
 }
 
-impl Store {
-    /// Creates a new store.
-    ///
-    /// # Arguments
-    /// *  `connection_info` - A connection string.
-    /// *  `ttl` - The time-to-live for records.
-    pub fn new<T>(
-        connection_info: T,
-        ttl: time::Duration,
-    ) -> Result<Self, Error>
-    where
-        T: redis::IntoConnectionInfo,
-    {
-        Ok(Self {
-            pool: r2d2::Pool::builder().build(
-                r2d2_redis::RedisConnectionManager::new(connection_info)?,
-            )?,
-            ttl,
-        })
-    }
+/// A function notified of changed messages.
+///
+/// It is called with the name of a message that has been deleted or
+/// replaced, or with `None` if any message may have changed.
+pub type Watcher = Box<dyn Fn(Option<&str>) + Send + Sync>;
 
+/// A store for messages.
+pub trait Store: Send + Sync {
     /// Reads a room description from the store.
     ///
+    /// If `id` is `None`, the entrance room is read.
+    ///
     /// # Arguments
     /// *  `message_name` - The name of the message.
     /// *  `id` - The room ID.
-    pub fn get(
-        &mut self,
+    fn get(
+        &self,
         message_name: &str,
         id: Option<xid::Identifier>,
-    ) -> Result<Option<messages::Room>, Error> {
-        let mut conn = self.pool.get()?;
+    ) -> Result<Option<messages::Room>, Error>;
 
-        Ok(conn.get(
-            id.map(|id| self.key(message_name, id))
-                .unwrap_or_else(|| message_name.into()),
-        )?)
+    /// Reads several room descriptions from the store.
+    ///
+    /// Rooms that do not exist are skipped, so the result may be shorter
+    /// than `ids`. Stores should read all rooms at once where possible.
+    ///
+    /// # Arguments
+    /// *  `message_name` - The name of the message.
+    /// *  `ids` - The room IDs.
+    fn get_rooms(
+        &self,
+        message_name: &str,
+        ids: &[xid::Identifier],
+    ) -> Result<Vec<messages::Room>, Error> {
+        ids.iter()
+            .filter_map(|&id| self.get(message_name, Some(id)).transpose())
+            .collect()
     }
 
     /// Checks whether a message exists.
     ///
     /// # Arguments
     /// *  `message_name` - The name of the message.
-    pub fn exists(&mut self, message_name: &str) -> Result<bool, Error> {
-        let mut conn = self.pool.get()?;
+    fn exists(&self, message_name: &str) -> Result<bool, Error>;
 
-        Ok(conn.exists(message_name)?)
-    }
+    /// Determines the time remaining until a message expires.
+    ///
+    /// If the message does not exist, `None` is returned.
+    ///
+    /// # Arguments
+    /// *  `message_name` - The name of the message.
+    fn expires_in(
+        &self,
+        message_name: &str,
+    ) -> Result<Option<time::Duration>, Error>;
+
+    /// Extends the lifetime of a message.
+    ///
+    /// The message and all its records will expire no sooner than its TTL
+    /// from now, but never later than `max_lifetime` after its creation. The
+    /// lifetime is never shortened.
+    ///
+    /// # Arguments
+    /// *  `message_name` - The name of the message.
+    /// *  `max_lifetime` - The maximum lifetime of the message.
+    fn touch(
+        &self,
+        message_name: &str,
+        max_lifetime: time::Duration,
+    ) -> Result<(), Error>;
 
     /// Stores an entire message in the store.
     ///
-    /// This method will fail if a message with the given name already exists.
+    /// Claiming the name and writing the rooms is a single atomic operation:
+    /// once this method returns, the message is either fully present or
+    /// absent. This method will fail with [`Error::Exists`] if a message with
+    /// the given name already exists or is being created.
+    ///
+    /// The recipe of the message is stored as well, so that it can be edited
+    /// later.
     ///
     /// # Arguments
     /// *  `message` - The message to store.
-    pub fn put_message(
-        &mut self,
+    /// *  `options` - The settings of the message.
+    fn put_message(
+        &self,
         message: &messages::Message,
-    ) -> Result<(), Error> {
-        let mut conn = self.pool.get()?;
-
-        if conn.exists(message.name())? {
-            Err(Error::Exists)
-        } else {
-            // First store the entrance room...
-            let entrance = message
-                .describe((0isize, 0isize).into())
-                .ok_or(Error::InternalError)?;
-            conn.set_ex::<_, _, ()>(
-                message.name(),
-                entrance,
-                self.ttl.as_secs() as usize,
-            )
-            .map_err(|_| Error::WriteError)?;
-
-            // ...then all the others
-            for room in message.rooms() {
-                conn.set_ex::<_, _, ()>(
-                    self.key(message.name(), room.xid),
-                    room,
-                    self.ttl.as_secs() as usize,
-                )
-                .map_err(|_| Error::WriteError)?;
-            }
-
-            Ok(())
-        }
-    }
+        options: &Options,
+    ) -> Result<(), Error>;
+
+    /// Reads the recipe of a message.
+    ///
+    /// # Arguments
+    /// *  `message_name` - The name of the message.
+    fn get_recipe(
+        &self,
+        message_name: &str,
+    ) -> Result<Option<messages::Recipe>, Error>;
+
+    /// Stores only the recipe and summary of a message.
+    ///
+    /// The rooms of such a message cannot be read with [`Store::get`]; they
+    /// must be regenerated from the recipe. This method will fail with
+    /// [`Error::Exists`] under the same conditions as [`Store::put_message`].
+    ///
+    /// # Arguments
+    /// *  `message` - The message whose recipe to store.
+    /// *  `options` - The settings of the message.
+    fn put_recipe(
+        &self,
+        message: &messages::Message,
+        options: &Options,
+    ) -> Result<(), Error>;
+
+    /// Replaces the rooms of an existing message.
+    ///
+    /// The message keeps its name, creation time, expiry and settings. All
+    /// rooms are swapped in a single atomic operation, so readers see either
+    /// the old or the new maze, never a mix. This method will fail with
+    /// [`Error::Missing`] if no message with the given name and owner exists,
+    /// and with [`Error::Exists`] if the message is being created or
+    /// replaced.
+    ///
+    /// The owner is checked as part of the atomic operation, so a message
+    /// that has been deleted and created again by someone else is never
+    /// replaced.
+    ///
+    /// # Arguments
+    /// *  `message` - The regenerated message.
+    /// *  `owner` - The hash of the token required to manage the message.
+    fn replace_message(
+        &self,
+        message: &messages::Message,
+        owner: &str,
+    ) -> Result<(), Error>;
+
+    /// Replaces an existing message by only the recipe and summary of a
+    /// regenerated one.
+    ///
+    /// This is the counterpart of [`Store::put_recipe`] for
+    /// [`Store::replace_message`], and fails under the same conditions.
+    ///
+    /// # Arguments
+    /// *  `message` - The regenerated message.
+    /// *  `owner` - The hash of the token required to manage the message.
+    fn replace_recipe(
+        &self,
+        message: &messages::Message,
+        owner: &str,
+    ) -> Result<(), Error>;
+
+    /// Reads the summary of a message and the time at which it was created.
+    ///
+    /// Messages stored before summaries were recorded have no summary.
+    ///
+    /// # Arguments
+    /// *  `message_name` - The name of the message.
+    fn get_summary(
+        &self,
+        message_name: &str,
+    ) -> Result<Option<(messages::Summary, time::SystemTime)>, Error>;
 
-    /// Generates the key for a room in a message.
+    /// Reads the hash of the token required to manage a message.
+    ///
+    /// If the message does not exist or cannot be managed, `None` is
+    /// returned.
     ///
     /// # Arguments
     /// *  `message_name` - The name of the message.
-    /// *  `id` - The ID of the room.
-    fn key(&self, message_name: &str, id: xid::Identifier) -> String {
-        format!("{}.{}", message_name, id)
+    fn get_owner(&self, message_name: &str) -> Result<Option<String>, Error>;
+
+    /// Checks whether the room identifiers of a message are encrypted per
+    /// session.
+    ///
+    /// If the message does not exist, `false` is returned.
+    ///
+    /// # Arguments
+    /// *  `message_name` - The name of the message.
+    fn is_private(&self, message_name: &str) -> Result<bool, Error>;
+
+    /// Removes a message and all its records, if it has a specific owner.
+    ///
+    /// The owner is checked atomically with the removal. This returns whether
+    /// a message was removed; nothing is removed if the message does not
+    /// exist or has another owner.
+    ///
+    /// # Arguments
+    /// *  `message_name` - The name of the message.
+    /// *  `owner` - The hash of the token required to manage the message.
+    fn delete(&self, message_name: &str, owner: &str) -> Result<bool, Error>;
+
+    /// Reads a persisted exploration.
+    ///
+    /// # Arguments
+    /// *  `token` - The exploration token.
+    fn get_exploration(
+        &self,
+        token: &str,
+    ) -> Result<Option<messages::Exploration>, Error>;
+
+    /// Reads the rooms visited in a persisted exploration, in the order they
+    /// were first visited.
+    ///
+    /// # Arguments
+    /// *  `token` - The exploration token.
+    fn get_visited(&self, token: &str) -> Result<Vec<xid::Identifier>, Error>;
+
+    /// Records a move in a persisted exploration.
+    ///
+    /// The room becomes the current room, and is added to the visited rooms
+    /// unless already visited. Only the move is written, so the cost does not
+    /// grow with the length of the exploration. An exploration of another
+    /// message with the same token is replaced.
+    ///
+    /// The move is only recorded if exactly `sequence` moves have been made
+    /// in the exploration, where an exploration that does not exist, or that
+    /// explores another message, has none. This returns whether the move was
+    /// recorded, so concurrent moves with the same sequence number succeed
+    /// at most once.
+    ///
+    /// # Arguments
+    /// *  `token` - The exploration token.
+    /// *  `message_name` - The name of the explored message.
+    /// *  `sequence` - The number of moves made before this one.
+    /// *  `id` - The identifier of the room moved to.
+    fn visit(
+        &self,
+        token: &str,
+        message_name: &str,
+        sequence: u64,
+        id: xid::Identifier,
+    ) -> Result<bool, Error>;
+
+    /// Records a single use nonce.
+    ///
+    /// # Arguments
+    /// *  `nonce` - The nonce.
+    /// *  `ttl` - The time after which the nonce can no longer be used.
+    fn put_nonce(&self, nonce: &str, ttl: time::Duration) -> Result<(), Error>;
+
+    /// Consumes a nonce recorded with [`Store::put_nonce`].
+    ///
+    /// This returns whether the nonce was recorded and had neither expired
+    /// nor already been consumed. Concurrent calls for the same nonce return
+    /// `true` at most once.
+    ///
+    /// # Arguments
+    /// *  `nonce` - The nonce.
+    fn take_nonce(&self, nonce: &str) -> Result<bool, Error>;
+
+    /// Registers a function to notify when a message is deleted or replaced,
+    /// possibly by another process sharing the storage.
+    ///
+    /// Stores that are never shared need not notify anyone.
+    ///
+    /// # Arguments
+    /// *  `watcher` - The function to notify.
+    fn watch(&self, _watcher: Watcher) -> Result<(), Error> {
+        Ok(())
     }
 }
 
-impl redis::FromRedisValue for messages::Room {
-    fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
-        match v {
-            redis::Value::Data(v) => rmp_serde::from_slice(v).map_err(|_| {
-                (redis::ErrorKind::TypeError, "invalid room data").into()
-            }),
-            _ => Err((redis::ErrorKind::TypeError, "invalid room data").into()),
-        }
-    }
+/// Converts a time in milliseconds since the epoch to a system time.
+///
+/// # Arguments
+/// *  `millis` - The number of milliseconds since the epoch.
+fn system_time(millis: i64) -> time::SystemTime {
+    time::UNIX_EPOCH + time::Duration::from_millis(millis.max(0) as u64)
 }
 
-impl redis::ToRedisArgs for messages::Room {
-    fn write_redis_args<W: ?Sized>(&self, out: &mut W)
-    where
-        W: redis::RedisWrite,
-    {
-        match rmp_serde::to_vec(self) {
-            Ok(v) => out.write_arg(&v),
-            Err(_) => log::warn!("Failed to write {:?} to redis", self),
-        }
-    }
+/// The current time, in milliseconds since the epoch.
+fn now() -> i64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
 }
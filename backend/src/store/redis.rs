@@ -0,0 +1,969 @@
+use std::thread;
+use std::time;
+
+use r2d2_redis;
+use r2d2_redis::r2d2;
+use r2d2_redis::redis;
+use r2d2_redis::redis::Commands;
+
+use crate::messages;
+use crate::messages::xid;
+
+use super::{now, system_time, Error, Options, Store, Watcher};
+
+/// A distributed store backed by Redis.
+#[derive(Clone)]
+pub struct RedisStore {
+    /// The connection information, used for subscriptions.
+    connection_info: redis::ConnectionInfo,
+
+    /// The connection pool.
+    pool: r2d2::Pool<r2d2_redis::RedisConnectionManager>,
+
+    /// The prefix of all keys.
+    prefix: String,
+
+    /// The TTL for records.
+    ttl: time::Duration,
+
+    /// The maximum number of rooms written in a single pipeline.
+    batch_size: usize,
+
+    /// Whether to fall back to reading messages stored before keys were
+    /// namespaced.
+    legacy: bool,
+
+    /// The random value identifying the changes published by this store.
+    origin: String,
+}
+
+impl RedisStore {
+    /// The time to wait before subscribing again after losing a
+    /// subscription.
+    const RESUBSCRIBE_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+    /// The time after which an abandoned reservation is released.
+    const RESERVATION_TTL: time::Duration = time::Duration::from_secs(60);
+
+    /// The script releasing a reservation, unless it has expired and been
+    /// taken by someone else.
+    const RELEASE_SCRIPT: &'static str = "
+        if redis.call('GET', KEYS[1]) == ARGV[1] then
+            return redis.call('DEL', KEYS[1])
+        else
+            return 0
+        end
+    ";
+
+    /// The hash field holding the entrance room.
+    ///
+    /// Room fields are zero padded hex strings, so this cannot collide with a
+    /// room.
+    const ENTRANCE_FIELD: &'static str = "entrance";
+
+    /// The hash field holding the recipe of a message.
+    const RECIPE_FIELD: &'static str = "recipe";
+
+    /// The hash field holding the creation time of a message, in
+    /// milliseconds since the epoch.
+    const CREATED_FIELD: &'static str = "created";
+
+    /// The hash field holding the time-to-live of a message, in
+    /// milliseconds.
+    const TTL_FIELD: &'static str = "ttl";
+
+    /// The hash field holding the summary of a message.
+    const SUMMARY_FIELD: &'static str = "summary";
+
+    /// The hash field holding the hash of the management token of a
+    /// message.
+    const OWNER_FIELD: &'static str = "owner";
+
+    /// The hash field present if the room identifiers of a message are
+    /// encrypted per session.
+    const PRIVATE_FIELD: &'static str = "private";
+
+    /// The position hash field holding the name of the explored message.
+    const MESSAGE_FIELD: &'static str = "message";
+
+    /// The position hash field holding the current room.
+    const CURRENT_FIELD: &'static str = "current";
+
+    /// The position hash field holding the number of moves made.
+    const SEQUENCE_FIELD: &'static str = "sequence";
+
+    /// The hash fields kept when a message is replaced.
+    const RETAINED_FIELDS: &'static [&'static str] = &[
+        Self::CREATED_FIELD,
+        Self::TTL_FIELD,
+        Self::OWNER_FIELD,
+        Self::PRIVATE_FIELD,
+    ];
+
+    /// Creates a new store.
+    ///
+    /// # Arguments
+    /// *  `connection_info` - A connection string.
+    /// *  `prefix` - The prefix of all keys.
+    /// *  `ttl` - The time-to-live for records.
+    /// *  `batch_size` - The maximum number of rooms written in a single
+    ///    pipeline.
+    /// *  `legacy` - Whether to fall back to reading messages stored under
+    ///    their raw names.
+    pub fn new<T>(
+        connection_info: T,
+        prefix: String,
+        ttl: time::Duration,
+        batch_size: usize,
+        legacy: bool,
+    ) -> Result<Self, Error>
+    where
+        T: redis::IntoConnectionInfo,
+    {
+        let connection_info = connection_info.into_connection_info()?;
+        Ok(Self {
+            pool: r2d2::Pool::builder().build(
+                r2d2_redis::RedisConnectionManager::new(
+                    connection_info.clone(),
+                )?,
+            )?,
+            connection_info,
+            prefix,
+            ttl,
+            batch_size: batch_size.max(1),
+            legacy,
+            origin: messages::generate_token(),
+        })
+    }
+
+    /// Generates a key.
+    ///
+    /// Keys have the form `prefix:namespace:name`, where the name is encoded
+    /// so that it never contains the separator. Keys in different namespaces
+    /// can therefore never collide, whatever the message names.
+    ///
+    /// # Arguments
+    /// *  `namespace` - The kind of record.
+    /// *  `message_name` - The name of the message.
+    fn key(&self, namespace: &str, message_name: &str) -> String {
+        format!("{}:{}:{}", self.prefix, namespace, encode(message_name))
+    }
+
+    /// Generates the key for the hash holding all rooms of a message.
+    ///
+    /// # Arguments
+    /// *  `message_name` - The name of the message.
+    fn message_key(&self, message_name: &str) -> String {
+        self.key("message", message_name)
+    }
+
+    /// Generates the key under which a message is written before it is
+    /// made visible.
+    ///
+    /// # Arguments
+    /// *  `message_name` - The name of the message.
+    fn staging_key(&self, message_name: &str) -> String {
+        self.key("staging", message_name)
+    }
+
+    /// Generates the key used to reserve a message name during creation or
+    /// replacement.
+    ///
+    /// # Arguments
+    /// *  `message_name` - The name of the message.
+    fn reservation_key(&self, message_name: &str) -> String {
+        self.key("reservation", message_name)
+    }
+
+    /// Generates the channel on which changed message names are published.
+    fn channel(&self) -> String {
+        format!("{}:changed", self.prefix)
+    }
+
+    /// Notifies all watchers that a message has changed.
+    ///
+    /// The message name is published following the origin of this store, so
+    /// that its own watcher, which has been notified already, can skip it.
+    /// Failing to notify is logged but otherwise ignored.
+    ///
+    /// # Arguments
+    /// *  `conn` - The connection to use.
+    /// *  `message_name` - The name of the message.
+    fn publish(&self, conn: &mut redis::Connection, message_name: &str) {
+        let payload = format!("{} {}", self.origin, message_name);
+        if let Err(e) = conn.publish::<_, _, ()>(self.channel(), payload) {
+            log::warn!("Failed to publish change of {}: {}", message_name, e);
+        }
+    }
+
+    /// Generates the key for the hash holding the current position of a
+    /// persisted exploration.
+    ///
+    /// # Arguments
+    /// *  `token` - The exploration token.
+    fn position_key(&self, token: &str) -> String {
+        self.key("position", token)
+    }
+
+    /// Generates the key for the sorted set holding the rooms visited in a
+    /// persisted exploration, scored by the time of the first visit.
+    ///
+    /// # Arguments
+    /// *  `token` - The exploration token.
+    fn visited_key(&self, token: &str) -> String {
+        self.key("visited", token)
+    }
+
+    /// Generates the key for a nonce.
+    ///
+    /// # Arguments
+    /// *  `nonce` - The nonce.
+    fn nonce_key(&self, nonce: &str) -> String {
+        self.key("nonce", nonce)
+    }
+
+    /// Reads a room description from a message stored under its raw name.
+    ///
+    /// Such messages were stored either as a single hash, or with the
+    /// entrance under the message name and every room under a key of its
+    /// own. They are never written and disappear once their TTL has passed.
+    ///
+    /// # Arguments
+    /// *  `conn` - The connection to use.
+    /// *  `message_name` - The name of the message.
+    /// *  `id` - The room ID.
+    fn get_legacy(
+        &self,
+        conn: &mut redis::Connection,
+        message_name: &str,
+        id: Option<xid::Identifier>,
+    ) -> Result<Option<messages::Room>, Error> {
+        let kind = redis::cmd("TYPE")
+            .arg(message_name)
+            .query::<String>(&mut *conn)?;
+        match kind.as_str() {
+            "hash" => Ok(conn.hget(message_name, self.field(id))?),
+            "string" => Ok(conn.get(
+                id.map(|id| format!("{}.{}", message_name, id))
+                    .unwrap_or_else(|| message_name.into()),
+            )?),
+            _ => Ok(None),
+        }
+    }
+
+    /// Generates the hash field for a room.
+    ///
+    /// # Arguments
+    /// *  `id` - The room ID, or `None` for the entrance.
+    fn field(&self, id: Option<xid::Identifier>) -> String {
+        id.map(|id| id.to_string())
+            .unwrap_or_else(|| Self::ENTRANCE_FIELD.into())
+    }
+
+    /// Runs a function while holding the reservation for a message name.
+    ///
+    /// This fails with [`Error::Exists`] if another creation, replacement or
+    /// removal of a message with the same name is in progress.
+    ///
+    /// The reservation holds a random value, and is only released if it
+    /// still holds that value. A reservation that expired during a slow
+    /// write, and was then taken by another request, is thus left in place.
+    ///
+    /// # Arguments
+    /// *  `conn` - The connection to use.
+    /// *  `message_name` - The name of the message.
+    /// *  `f` - The function to run.
+    fn reserved<F, T>(
+        &self,
+        conn: &mut redis::Connection,
+        message_name: &str,
+        f: F,
+    ) -> Result<T, Error>
+    where
+        F: FnOnce(&mut redis::Connection) -> Result<T, Error>,
+    {
+        let reservation = self.reservation_key(message_name);
+        let holder = messages::generate_token();
+        let claimed = redis::cmd("SET")
+            .arg(&reservation)
+            .arg(&holder)
+            .arg("NX")
+            .arg("PX")
+            .arg(Self::RESERVATION_TTL.as_millis() as u64)
+            .query::<Option<String>>(&mut *conn)?
+            .is_some();
+        if !claimed {
+            return Err(Error::Exists);
+        }
+
+        let result = f(&mut *conn);
+        let released = redis::Script::new(Self::RELEASE_SCRIPT)
+            .key(&reservation)
+            .arg(&holder)
+            .invoke::<usize>(&mut *conn);
+        match released {
+            Ok(0) => log::warn!("Lost reservation {}", reservation),
+            Ok(_) => {}
+            Err(_) => {
+                log::warn!("Failed to release reservation {}", reservation)
+            }
+        }
+
+        result
+    }
+
+    /// Generates the metadata fields of a message.
+    ///
+    /// # Arguments
+    /// *  `message` - The message.
+    /// *  `options` - The settings of the message.
+    fn metadata(
+        &self,
+        message: &messages::Message,
+        options: &Options,
+    ) -> Result<Vec<(&'static str, Vec<u8>)>, Error> {
+        let ttl = options.ttl.unwrap_or(self.ttl).as_millis();
+        let mut fields = vec![
+            (Self::CREATED_FIELD, now().to_string().into_bytes()),
+            (Self::TTL_FIELD, ttl.to_string().into_bytes()),
+            (Self::SUMMARY_FIELD, serialize(&message.summary())?),
+        ];
+        if let Some(owner) = &options.owner {
+            fields.push((Self::OWNER_FIELD, owner.clone().into_bytes()));
+        }
+        if options.private {
+            fields.push((Self::PRIVATE_FIELD, b"1".to_vec()));
+        }
+        Ok(fields)
+    }
+
+    /// Reads the fields of a message that are kept when it is replaced, and
+    /// the time remaining until it expires, in milliseconds.
+    ///
+    /// This fails with [`Error::Missing`] if no message with the given owner
+    /// exists. The caller must hold the reservation for the message name, so
+    /// that the owner cannot change before the message is replaced.
+    ///
+    /// # Arguments
+    /// *  `conn` - The connection to use.
+    /// *  `message_name` - The name of the message.
+    /// *  `owner` - The hash of the token required to manage the message.
+    fn retained(
+        &self,
+        conn: &mut redis::Connection,
+        message_name: &str,
+        owner: &str,
+    ) -> Result<(Vec<(&'static str, Vec<u8>)>, usize), Error> {
+        let key = self.message_key(message_name);
+        let ttl = match conn.pttl::<_, i64>(&key)? {
+            -2 => return Err(Error::Missing),
+            -1 => self.ttl.as_millis() as usize,
+            remaining => remaining as usize,
+        };
+        let values: Vec<Option<Vec<u8>>> = redis::cmd("HMGET")
+            .arg(&key)
+            .arg(Self::RETAINED_FIELDS)
+            .query(&mut *conn)?;
+        let fields = Self::RETAINED_FIELDS
+            .iter()
+            .zip(values)
+            .filter_map(|(&field, value)| value.map(|value| (field, value)))
+            .collect::<Vec<_>>();
+        let owned = fields.iter().any(|(field, value)| {
+            *field == Self::OWNER_FIELD && value.as_slice() == owner.as_bytes()
+        });
+        if owned {
+            Ok((fields, ttl))
+        } else {
+            Err(Error::Missing)
+        }
+    }
+
+    /// Writes all records of a message.
+    ///
+    /// The rooms are written to a staging hash in pipelines of at most
+    /// `batch_size` rooms. The remaining fields are added last, and the
+    /// staging hash is then renamed to the message key, which makes the
+    /// message visible, or swaps all its rooms, atomically. The caller must
+    /// hold the reservation for the message name. If any write fails, the
+    /// staging hash is removed.
+    ///
+    /// # Arguments
+    /// *  `conn` - The connection to use.
+    /// *  `message_name` - The name of the message.
+    /// *  `rooms` - The rooms of the message.
+    /// *  `fields` - The remaining fields of the message.
+    /// *  `ttl` - The time-to-live of the message, in milliseconds.
+    /// *  `replace` - Whether to replace an existing message rather than
+    ///    create a new one.
+    fn write(
+        &self,
+        conn: &mut redis::Connection,
+        message_name: &str,
+        rooms: &[messages::Room],
+        fields: &[(&'static str, Vec<u8>)],
+        ttl: usize,
+        replace: bool,
+    ) -> Result<(), Error> {
+        let key = self.message_key(message_name);
+        match (conn.exists(&key)?, replace) {
+            (true, false) => return Err(Error::Exists),
+            (false, true) => return Err(Error::Missing),
+            _ => {}
+        }
+
+        let staging = self.staging_key(message_name);
+        let renamed = rooms
+            .chunks(self.batch_size)
+            .try_for_each(|chunk| {
+                let mut pipe = redis::pipe();
+                for room in chunk {
+                    pipe.hset(&staging, self.field(Some(room.xid)), room)
+                        .ignore();
+                }
+                pipe.pexpire(&staging, ttl).ignore();
+                pipe.query::<()>(&mut *conn)
+            })
+            .and_then(|_| {
+                let mut pipe = redis::pipe();
+                pipe.atomic()
+                    .hset_multiple(&staging, fields)
+                    .ignore()
+                    .pexpire(&staging, ttl)
+                    .ignore();
+                if replace {
+                    pipe.rename(&staging, &key)
+                        .ignore()
+                        .query::<()>(&mut *conn)
+                        .map(|_| true)
+                } else {
+                    pipe.rename_nx(&staging, &key)
+                        .query::<(bool,)>(&mut *conn)
+                        .map(|(renamed,)| renamed)
+                }
+            });
+
+        match renamed {
+            Ok(true) => Ok(()),
+            result => {
+                if conn.del::<_, ()>(&staging).is_err() {
+                    log::warn!("Failed to roll back {}", message_name);
+                }
+                match result {
+                    Ok(_) => Err(Error::Exists),
+                    Err(_) => Err(Error::WriteError),
+                }
+            }
+        }
+    }
+}
+
+impl Store for RedisStore {
+    fn get(
+        &self,
+        message_name: &str,
+        id: Option<xid::Identifier>,
+    ) -> Result<Option<messages::Room>, Error> {
+        let mut conn = self.pool.get()?;
+
+        let key = self.message_key(message_name);
+        let room: Option<messages::Room> = conn.hget(&key, self.field(id))?;
+        if room.is_none() && self.legacy && !conn.exists(&key)? {
+            self.get_legacy(&mut conn, message_name, id)
+        } else {
+            Ok(room)
+        }
+    }
+
+    fn get_rooms(
+        &self,
+        message_name: &str,
+        ids: &[xid::Identifier],
+    ) -> Result<Vec<messages::Room>, Error> {
+        let mut conn = self.pool.get()?;
+
+        // Legacy messages are read room by room
+        let key = self.message_key(message_name);
+        if self.legacy && !conn.exists(&key)? {
+            return ids
+                .iter()
+                .filter_map(|&id| {
+                    self.get_legacy(&mut conn, message_name, Some(id))
+                        .transpose()
+                })
+                .collect();
+        }
+
+        let mut rooms = Vec::with_capacity(ids.len());
+        for chunk in ids.chunks(self.batch_size) {
+            let fields = chunk
+                .iter()
+                .map(|&id| self.field(Some(id)))
+                .collect::<Vec<_>>();
+            let values: Vec<Option<messages::Room>> = redis::cmd("HMGET")
+                .arg(&key)
+                .arg(fields)
+                .query(&mut *conn)?;
+            rooms.extend(values.into_iter().flatten());
+        }
+        Ok(rooms)
+    }
+
+    fn exists(&self, message_name: &str) -> Result<bool, Error> {
+        let mut conn = self.pool.get()?;
+
+        Ok(conn.exists(self.message_key(message_name))?
+            || (self.legacy && conn.exists(message_name)?))
+    }
+
+    fn expires_in(
+        &self,
+        message_name: &str,
+    ) -> Result<Option<time::Duration>, Error> {
+        let mut conn = self.pool.get()?;
+
+        let mut ttl: i64 = conn.pttl(self.message_key(message_name))?;
+        if ttl == -2 && self.legacy {
+            ttl = conn.pttl(message_name)?;
+        }
+        Ok(match ttl {
+            -2 => None,
+            -1 => Some(self.ttl),
+            ttl => Some(time::Duration::from_millis(ttl as u64)),
+        })
+    }
+
+    fn touch(
+        &self,
+        message_name: &str,
+        max_lifetime: time::Duration,
+    ) -> Result<(), Error> {
+        let mut conn = self.pool.get()?;
+
+        // Messages stored before their creation time was recorded, and
+        // legacy messages, are never extended
+        let key = self.message_key(message_name);
+        let (created, ttl): (Option<i64>, Option<i64>) = redis::cmd("HMGET")
+            .arg(&key)
+            .arg(Self::CREATED_FIELD)
+            .arg(Self::TTL_FIELD)
+            .query(&mut *conn)?;
+        if let Some(created) = created {
+            let now = now();
+            let ttl = ttl.unwrap_or(self.ttl.as_millis() as i64);
+            let expires =
+                (now + ttl).min(created + max_lifetime.as_millis() as i64);
+            if expires > now {
+                // The rooms share the key, so they are all extended at once
+                let remaining: i64 = conn.pttl(&key)?;
+                if remaining >= 0 && now + remaining < expires {
+                    conn.pexpire_at::<_, ()>(&key, expires as usize)
+                        .map_err(|_| Error::WriteError)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn put_message(
+        &self,
+        message: &messages::Message,
+        options: &Options,
+    ) -> Result<(), Error> {
+        let mut conn = self.pool.get()?;
+
+        let entrance = message
+            .describe((0isize, 0isize).into())
+            .ok_or(Error::InternalError)?;
+        let rooms = message.rooms().collect::<Vec<_>>();
+        let mut fields = self.metadata(message, options)?;
+        fields.push((Self::ENTRANCE_FIELD, serialize(&entrance)?));
+        fields.push((Self::RECIPE_FIELD, serialize(message.recipe())?));
+        let ttl = options.ttl.unwrap_or(self.ttl).as_millis() as usize;
+        self.reserved(&mut conn, message.name(), |conn| {
+            self.write(conn, message.name(), &rooms, &fields, ttl, false)
+        })?;
+        self.publish(&mut conn, message.name());
+        Ok(())
+    }
+
+    fn get_recipe(
+        &self,
+        message_name: &str,
+    ) -> Result<Option<messages::Recipe>, Error> {
+        let mut conn = self.pool.get()?;
+
+        conn.hget::<_, _, Option<Vec<u8>>>(
+            self.message_key(message_name),
+            Self::RECIPE_FIELD,
+        )?
+        .map(|data| rmp_serde::from_slice(&data).map_err(|_| Error::ReadError))
+        .transpose()
+    }
+
+    fn put_recipe(
+        &self,
+        message: &messages::Message,
+        options: &Options,
+    ) -> Result<(), Error> {
+        let mut conn = self.pool.get()?;
+
+        let mut fields = self.metadata(message, options)?;
+        fields.push((Self::RECIPE_FIELD, serialize(message.recipe())?));
+        let ttl = options.ttl.unwrap_or(self.ttl).as_millis() as usize;
+        self.reserved(&mut conn, message.name(), |conn| {
+            self.write(conn, message.name(), &[], &fields, ttl, false)
+        })?;
+        self.publish(&mut conn, message.name());
+        Ok(())
+    }
+
+    fn replace_message(
+        &self,
+        message: &messages::Message,
+        owner: &str,
+    ) -> Result<(), Error> {
+        let mut conn = self.pool.get()?;
+
+        let entrance = message
+            .describe((0isize, 0isize).into())
+            .ok_or(Error::InternalError)?;
+        let rooms = message.rooms().collect::<Vec<_>>();
+        let mut fields = vec![
+            (Self::ENTRANCE_FIELD, serialize(&entrance)?),
+            (Self::RECIPE_FIELD, serialize(message.recipe())?),
+            (Self::SUMMARY_FIELD, serialize(&message.summary())?),
+        ];
+        self.reserved(&mut conn, message.name(), |conn| {
+            let (retained, ttl) = self.retained(conn, message.name(), owner)?;
+            fields.extend(retained);
+            self.write(conn, message.name(), &rooms, &fields, ttl, true)
+        })?;
+        self.publish(&mut conn, message.name());
+        Ok(())
+    }
+
+    fn replace_recipe(
+        &self,
+        message: &messages::Message,
+        owner: &str,
+    ) -> Result<(), Error> {
+        let mut conn = self.pool.get()?;
+
+        let mut fields = vec![
+            (Self::RECIPE_FIELD, serialize(message.recipe())?),
+            (Self::SUMMARY_FIELD, serialize(&message.summary())?),
+        ];
+        self.reserved(&mut conn, message.name(), |conn| {
+            let (retained, ttl) = self.retained(conn, message.name(), owner)?;
+            fields.extend(retained);
+            self.write(conn, message.name(), &[], &fields, ttl, true)
+        })?;
+        self.publish(&mut conn, message.name());
+        Ok(())
+    }
+
+    fn get_owner(&self, message_name: &str) -> Result<Option<String>, Error> {
+        let mut conn = self.pool.get()?;
+
+        Ok(conn.hget(self.message_key(message_name), Self::OWNER_FIELD)?)
+    }
+
+    fn is_private(&self, message_name: &str) -> Result<bool, Error> {
+        let mut conn = self.pool.get()?;
+
+        Ok(conn.hexists(self.message_key(message_name), Self::PRIVATE_FIELD)?)
+    }
+
+    fn delete(&self, message_name: &str, owner: &str) -> Result<bool, Error> {
+        let mut conn = self.pool.get()?;
+
+        // Holding the reservation prevents a concurrent replacement from
+        // renaming its staged message over the removed one
+        let deleted = self.reserved(&mut conn, message_name, |conn| {
+            let key = self.message_key(message_name);
+            let current: Option<String> = conn.hget(&key, Self::OWNER_FIELD)?;
+            if current.as_deref() != Some(owner) {
+                return Ok(false);
+            }
+
+            // All rooms share the key, so they are all removed at once
+            let deleted: usize =
+                conn.del(&key).map_err(|_| Error::WriteError)?;
+            Ok(deleted > 0)
+        })?;
+        if deleted {
+            self.publish(&mut conn, message_name);
+        }
+        Ok(deleted)
+    }
+
+    fn get_summary(
+        &self,
+        message_name: &str,
+    ) -> Result<Option<(messages::Summary, time::SystemTime)>, Error> {
+        let mut conn = self.pool.get()?;
+
+        let (summary, created): (Option<Vec<u8>>, Option<i64>) =
+            redis::cmd("HMGET")
+                .arg(self.message_key(message_name))
+                .arg(Self::SUMMARY_FIELD)
+                .arg(Self::CREATED_FIELD)
+                .query(&mut *conn)?;
+        match (summary, created) {
+            (Some(summary), Some(created)) => rmp_serde::from_slice(&summary)
+                .map(|summary| Some((summary, system_time(created))))
+                .map_err(|_| Error::ReadError),
+            _ => Ok(None),
+        }
+    }
+
+    fn get_exploration(
+        &self,
+        token: &str,
+    ) -> Result<Option<messages::Exploration>, Error> {
+        let mut conn = self.pool.get()?;
+
+        let (message, current, sequence): (
+            Option<String>,
+            Option<String>,
+            Option<u64>,
+        ) = redis::cmd("HMGET")
+            .arg(self.position_key(token))
+            .arg(Self::MESSAGE_FIELD)
+            .arg(Self::CURRENT_FIELD)
+            .arg(Self::SEQUENCE_FIELD)
+            .query(&mut *conn)?;
+        match (message, current) {
+            (Some(message), Some(current)) => Ok(Some(messages::Exploration {
+                message,
+                current: current.parse().map_err(|_| Error::ReadError)?,
+                sequence: sequence.unwrap_or(0),
+            })),
+            _ => Ok(None),
+        }
+    }
+
+    fn get_visited(&self, token: &str) -> Result<Vec<xid::Identifier>, Error> {
+        let mut conn = self.pool.get()?;
+
+        conn.zrange::<_, Vec<String>>(self.visited_key(token), 0, -1)?
+            .iter()
+            .map(|id| id.parse().map_err(|_| Error::ReadError))
+            .collect()
+    }
+
+    fn visit(
+        &self,
+        token: &str,
+        message_name: &str,
+        sequence: u64,
+        id: xid::Identifier,
+    ) -> Result<bool, Error> {
+        let mut conn = self.pool.get()?;
+
+        let position = self.position_key(token);
+        let visited = self.visited_key(token);
+        let ttl = self.ttl.as_millis() as usize;
+
+        // The position is watched, so a concurrent move aborts the
+        // transaction and the sequence number is checked again
+        redis::transaction(&mut *conn, &[&position], |conn, pipe| {
+            let (message, moves): (Option<String>, Option<u64>) =
+                redis::cmd("HMGET")
+                    .arg(&position)
+                    .arg(Self::MESSAGE_FIELD)
+                    .arg(Self::SEQUENCE_FIELD)
+                    .query(&mut *conn)?;
+            let explored = message.as_deref() == Some(message_name);
+            let moves = if explored { moves.unwrap_or(0) } else { 0 };
+            if moves != sequence {
+                return Ok(Some(false));
+            }
+
+            // Only the move is written; the visits of an exploration of
+            // another message are discarded
+            if !explored {
+                pipe.del(&visited).ignore();
+            }
+            pipe.hset_multiple(
+                &position,
+                &[
+                    (Self::MESSAGE_FIELD, message_name.to_string()),
+                    (Self::CURRENT_FIELD, id.to_string()),
+                    (Self::SEQUENCE_FIELD, (sequence + 1).to_string()),
+                ],
+            )
+            .ignore()
+            .cmd("ZADD")
+            .arg(&visited)
+            .arg("NX")
+            .arg(now())
+            .arg(id.to_string())
+            .ignore()
+            .pexpire(&position, ttl)
+            .ignore()
+            .pexpire(&visited, ttl)
+            .ignore()
+            .query::<Option<()>>(&mut *conn)
+            .map(|done| done.map(|_| true))
+        })
+        .map_err(|_| Error::WriteError)
+    }
+
+    fn put_nonce(&self, nonce: &str, ttl: time::Duration) -> Result<(), Error> {
+        let mut conn = self.pool.get()?;
+
+        conn.pset_ex::<_, _, ()>(
+            self.nonce_key(nonce),
+            1,
+            ttl.as_millis() as usize,
+        )
+        .map_err(|_| Error::WriteError)
+    }
+
+    fn take_nonce(&self, nonce: &str) -> Result<bool, Error> {
+        let mut conn = self.pool.get()?;
+
+        // Only one of several concurrent deletions removes the key
+        let deleted: usize = conn
+            .del(self.nonce_key(nonce))
+            .map_err(|_| Error::WriteError)?;
+        Ok(deleted > 0)
+    }
+
+    fn watch(&self, watcher: Watcher) -> Result<(), Error> {
+        let client = redis::Client::open(self.connection_info.clone())?;
+        let channel = self.channel();
+        let origin = self.origin.clone();
+        thread::spawn(move || loop {
+            if let Err(e) = subscribe(&client, &channel, &origin, &watcher) {
+                log::warn!("Lost subscription to {}: {}", channel, e);
+            }
+            thread::sleep(Self::RESUBSCRIBE_INTERVAL);
+        });
+        Ok(())
+    }
+}
+
+/// Notifies a watcher of every message name published on a channel by
+/// other stores.
+///
+/// Changes may have been missed while not subscribed, so the watcher is
+/// notified that any message may have changed once the subscription is
+/// confirmed. This only returns once the subscription fails.
+///
+/// # Arguments
+/// *  `client` - The client used to connect.
+/// *  `channel` - The channel.
+/// *  `origin` - The origin of changes published by the watched store.
+/// *  `watcher` - The function to notify.
+fn subscribe(
+    client: &redis::Client,
+    channel: &str,
+    origin: &str,
+    watcher: &Watcher,
+) -> redis::RedisResult<()> {
+    let mut conn = client.get_connection()?;
+    let mut pubsub = conn.as_pubsub();
+    pubsub.subscribe(channel)?;
+    watcher(None);
+    loop {
+        let payload = pubsub.get_message()?.get_payload::<String>()?;
+        if let Some(message_name) = changed(&payload, origin) {
+            watcher(Some(message_name));
+        }
+    }
+}
+
+/// Extracts the name of a changed message from a published payload.
+///
+/// Payloads have the form `origin name`; payloads without an origin were
+/// published by earlier versions. If the change was published with the
+/// origin passed, `None` is returned.
+///
+/// # Arguments
+/// *  `payload` - The published payload.
+/// *  `origin` - The origin of changes to skip.
+fn changed<'a>(payload: &'a str, origin: &str) -> Option<&'a str> {
+    let mut parts = payload.splitn(2, ' ');
+    match (parts.next(), parts.next()) {
+        (Some(from), Some(_)) if from == origin => None,
+        (Some(_), Some(message_name)) => Some(message_name),
+        _ => Some(payload),
+    }
+}
+
+/// Encodes a message name for use in a key.
+///
+/// All bytes except ASCII alphanumerics, `-`, `_` and `.` are percent encoded.
+///
+/// # Arguments
+/// *  `message_name` - The name of the message.
+fn encode(message_name: &str) -> String {
+    let mut result = String::with_capacity(message_name.len());
+    for b in message_name.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' => {
+                result.push(b as char)
+            }
+            _ => result.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    result
+}
+
+/// Serializes a record for storage.
+///
+/// # Arguments
+/// *  `value` - The record to serialize.
+fn serialize<T>(value: &T) -> Result<Vec<u8>, Error>
+where
+    T: serde::Serialize,
+{
+    rmp_serde::to_vec(value).map_err(|_| Error::WriteError)
+}
+
+impl redis::FromRedisValue for messages::Room {
+    fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
+        match v {
+            redis::Value::Data(v) => rmp_serde::from_slice(v).map_err(|_| {
+                (redis::ErrorKind::TypeError, "invalid room data").into()
+            }),
+            _ => Err((redis::ErrorKind::TypeError, "invalid room data").into()),
+        }
+    }
+}
+
+impl redis::ToRedisArgs for messages::Room {
+    fn write_redis_args<W: ?Sized>(&self, out: &mut W)
+    where
+        W: redis::RedisWrite,
+    {
+        match rmp_serde::to_vec(self) {
+            Ok(v) => out.write_arg(&v),
+            Err(_) => log::warn!("Failed to write {:?} to redis", self),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_separates_keys() {
+        assert_eq!(encode("hello-world_1.txt"), "hello-world_1.txt");
+        assert_eq!(encode("a:b"), "a%3Ab");
+        assert_eq!(encode("a%3Ab"), "a%253Ab");
+        assert_eq!(encode("é"), "%C3%A9");
+        assert!(!encode("message:a:b").contains(':'));
+    }
+
+    #[test]
+    fn changed_skips_own_changes() {
+        assert_eq!(changed("origin test", "origin"), None);
+        assert_eq!(changed("other test", "origin"), Some("test"));
+        assert_eq!(changed("test", "origin"), Some("test"));
+    }
+}
@@ -0,0 +1,960 @@
+use std::collections;
+use std::path;
+use std::sync;
+use std::thread;
+use std::time;
+
+use rusqlite::{params, OptionalExtension};
+
+use crate::messages;
+use crate::messages::xid;
+
+use super::{now, system_time, Error, Options, Store};
+
+/// The database schema.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS messages (
+        name TEXT PRIMARY KEY,
+        entrance BLOB NOT NULL,
+        expires INTEGER NOT NULL,
+        created INTEGER NOT NULL DEFAULT 0,
+        ttl INTEGER,
+        summary BLOB,
+        owner TEXT,
+        private INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE TABLE IF NOT EXISTS rooms (
+        message TEXT NOT NULL,
+        xid TEXT NOT NULL,
+        room BLOB NOT NULL,
+        PRIMARY KEY (message, xid)
+    );
+    CREATE TABLE IF NOT EXISTS recipes (
+        message TEXT PRIMARY KEY,
+        recipe BLOB NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS positions (
+        token TEXT PRIMARY KEY,
+        message TEXT NOT NULL,
+        current TEXT NOT NULL,
+        sequence INTEGER NOT NULL DEFAULT 0,
+        expires INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS visits (
+        token TEXT NOT NULL,
+        xid TEXT NOT NULL,
+        PRIMARY KEY (token, xid)
+    );
+    CREATE TABLE IF NOT EXISTS nonces (
+        nonce TEXT PRIMARY KEY,
+        expires INTEGER NOT NULL
+    );
+";
+
+/// A persistent store backed by an SQLite database file.
+///
+/// Messages stored by recipe have an empty entrance and no rooms.
+pub struct SqliteStore {
+    /// The database connection, shared with the expiry sweeper.
+    connection: sync::Arc<sync::Mutex<rusqlite::Connection>>,
+
+    /// The TTL for records.
+    ttl: time::Duration,
+}
+
+impl SqliteStore {
+    /// The maximum number of rooms read in a single query.
+    ///
+    /// SQLite limits the number of parameters of a statement, to 999 in
+    /// older versions.
+    const MAX_BATCH_SIZE: usize = 500;
+
+    /// Opens a store, creating the database if it does not exist.
+    ///
+    /// This will also start a background thread that removes expired
+    /// messages every `sweep_interval`. The thread terminates once the store
+    /// is dropped.
+    ///
+    /// # Arguments
+    /// *  `path` - The path to the database file.
+    /// *  `ttl` - The time-to-live for records.
+    /// *  `sweep_interval` - The interval between removals of expired
+    ///    messages.
+    pub fn new<P>(
+        path: P,
+        ttl: time::Duration,
+        sweep_interval: time::Duration,
+    ) -> Result<Self, Error>
+    where
+        P: AsRef<path::Path>,
+    {
+        let connection = rusqlite::Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+        migrate(&connection)?;
+        let connection = sync::Arc::new(sync::Mutex::new(connection));
+
+        let weak = sync::Arc::downgrade(&connection);
+        thread::spawn(move || loop {
+            thread::sleep(sweep_interval);
+            match weak.upgrade() {
+                Some(connection) => match connection.lock() {
+                    Ok(connection) => {
+                        if let Err(e) = sweep(&connection, None) {
+                            log::warn!("Failed to remove expired: {}", e);
+                        }
+                    }
+                    Err(_) => break,
+                },
+                None => break,
+            }
+        });
+
+        Ok(Self { connection, ttl })
+    }
+
+    /// Locks the database connection.
+    fn connection(
+        &self,
+    ) -> Result<sync::MutexGuard<'_, rusqlite::Connection>, Error> {
+        self.connection.lock().map_err(|_| Error::InternalError)
+    }
+}
+
+impl Store for SqliteStore {
+    fn get(
+        &self,
+        message_name: &str,
+        id: Option<xid::Identifier>,
+    ) -> Result<Option<messages::Room>, Error> {
+        let connection = self.connection()?;
+        let data: Option<Vec<u8>> = match id {
+            Some(id) => connection.query_row(
+                "SELECT rooms.room FROM rooms
+                    JOIN messages ON messages.name = rooms.message
+                    WHERE rooms.message = ?1 AND rooms.xid = ?2
+                        AND messages.expires > ?3",
+                params![message_name, id.to_string(), now()],
+                |row| row.get::<_, Vec<u8>>(0),
+            ),
+            None => connection.query_row(
+                "SELECT entrance FROM messages
+                    WHERE name = ?1 AND expires > ?2
+                        AND length(entrance) > 0",
+                params![message_name, now()],
+                |row| row.get::<_, Vec<u8>>(0),
+            ),
+        }
+        .optional()
+        .map_err(|_| Error::ReadError)?;
+
+        data.map(|data| {
+            rmp_serde::from_slice(&data).map_err(|_| Error::ReadError)
+        })
+        .transpose()
+    }
+
+    fn get_rooms(
+        &self,
+        message_name: &str,
+        ids: &[xid::Identifier],
+    ) -> Result<Vec<messages::Room>, Error> {
+        let now = now();
+        let connection = self.connection()?;
+        let mut found = collections::HashMap::with_capacity(ids.len());
+        for chunk in ids.chunks(Self::MAX_BATCH_SIZE) {
+            let mut statement = connection.prepare(&format!(
+                "SELECT rooms.xid, rooms.room FROM rooms
+                    JOIN messages ON messages.name = rooms.message
+                    WHERE rooms.message = ?1 AND messages.expires > ?2
+                        AND rooms.xid IN ({})",
+                vec!["?"; chunk.len()].join(", "),
+            ))?;
+            let xids =
+                chunk.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+            let mut parameters: Vec<&dyn rusqlite::ToSql> =
+                vec![&message_name, &now];
+            parameters
+                .extend(xids.iter().map(|xid| xid as &dyn rusqlite::ToSql));
+            let mut rows =
+                statement.query(parameters).map_err(|_| Error::ReadError)?;
+            while let Some(row) = rows.next().map_err(|_| Error::ReadError)? {
+                found.insert(
+                    row.get::<_, String>(0).map_err(|_| Error::ReadError)?,
+                    row.get::<_, Vec<u8>>(1).map_err(|_| Error::ReadError)?,
+                );
+            }
+        }
+
+        // The rooms are returned in the order requested
+        ids.iter()
+            .filter_map(|id| found.get(&id.to_string()))
+            .map(|data| {
+                rmp_serde::from_slice(data).map_err(|_| Error::ReadError)
+            })
+            .collect()
+    }
+
+    fn exists(&self, message_name: &str) -> Result<bool, Error> {
+        Ok(self
+            .connection()?
+            .query_row(
+                "SELECT 1 FROM messages WHERE name = ?1 AND expires > ?2",
+                params![message_name, now()],
+                |_| Ok(()),
+            )
+            .optional()
+            .map_err(|_| Error::ReadError)?
+            .is_some())
+    }
+
+    fn expires_in(
+        &self,
+        message_name: &str,
+    ) -> Result<Option<time::Duration>, Error> {
+        let now = now();
+        let expires = self
+            .connection()?
+            .query_row(
+                "SELECT expires FROM messages WHERE name = ?1 AND expires > ?2",
+                params![message_name, now],
+                |row| row.get::<_, i64>(0),
+            )
+            .optional()
+            .map_err(|_| Error::ReadError)?;
+
+        Ok(expires
+            .map(|expires| time::Duration::from_millis((expires - now) as u64)))
+    }
+
+    fn put_message(
+        &self,
+        message: &messages::Message,
+        options: &Options,
+    ) -> Result<(), Error> {
+        let entrance = message
+            .describe((0isize, 0isize).into())
+            .ok_or(Error::InternalError)?;
+
+        let mut connection = self.connection()?;
+        let transaction = connection.transaction()?;
+        claim(
+            &transaction,
+            message,
+            &serialize(&entrance)?,
+            options.ttl.unwrap_or(self.ttl),
+            options,
+        )?;
+        insert_rooms(&transaction, message)?;
+        insert_recipe(&transaction, message)?;
+
+        transaction.commit().map_err(|_| Error::WriteError)
+    }
+
+    fn touch(
+        &self,
+        message_name: &str,
+        max_lifetime: time::Duration,
+    ) -> Result<(), Error> {
+        let now = now();
+        self.connection()?
+            .execute(
+                "UPDATE messages
+                    SET expires = MAX(
+                        expires,
+                        MIN(?2 + COALESCE(ttl, ?3), created + ?4)
+                    )
+                    WHERE name = ?1 AND expires > ?2",
+                params![
+                    message_name,
+                    now,
+                    self.ttl.as_millis() as i64,
+                    max_lifetime.as_millis() as i64,
+                ],
+            )
+            .map_err(|_| Error::WriteError)?;
+        Ok(())
+    }
+
+    fn get_recipe(
+        &self,
+        message_name: &str,
+    ) -> Result<Option<messages::Recipe>, Error> {
+        let data = self
+            .connection()?
+            .query_row(
+                "SELECT recipes.recipe FROM recipes
+                    JOIN messages ON messages.name = recipes.message
+                    WHERE recipes.message = ?1 AND messages.expires > ?2",
+                params![message_name, now()],
+                |row| row.get::<_, Vec<u8>>(0),
+            )
+            .optional()
+            .map_err(|_| Error::ReadError)?;
+
+        data.map(|data| {
+            rmp_serde::from_slice(&data).map_err(|_| Error::ReadError)
+        })
+        .transpose()
+    }
+
+    fn put_recipe(
+        &self,
+        message: &messages::Message,
+        options: &Options,
+    ) -> Result<(), Error> {
+        let mut connection = self.connection()?;
+        let transaction = connection.transaction()?;
+        claim(
+            &transaction,
+            message,
+            &[],
+            options.ttl.unwrap_or(self.ttl),
+            options,
+        )?;
+        insert_recipe(&transaction, message)?;
+
+        transaction.commit().map_err(|_| Error::WriteError)
+    }
+
+    fn replace_message(
+        &self,
+        message: &messages::Message,
+        owner: &str,
+    ) -> Result<(), Error> {
+        let entrance = message
+            .describe((0isize, 0isize).into())
+            .ok_or(Error::InternalError)?;
+
+        let mut connection = self.connection()?;
+        let transaction = connection.transaction()?;
+        clear(&transaction, message, owner, &serialize(&entrance)?)?;
+        insert_rooms(&transaction, message)?;
+        insert_recipe(&transaction, message)?;
+
+        transaction.commit().map_err(|_| Error::WriteError)
+    }
+
+    fn replace_recipe(
+        &self,
+        message: &messages::Message,
+        owner: &str,
+    ) -> Result<(), Error> {
+        let mut connection = self.connection()?;
+        let transaction = connection.transaction()?;
+        clear(&transaction, message, owner, &[])?;
+        insert_recipe(&transaction, message)?;
+
+        transaction.commit().map_err(|_| Error::WriteError)
+    }
+
+    fn get_owner(&self, message_name: &str) -> Result<Option<String>, Error> {
+        Ok(self
+            .connection()?
+            .query_row(
+                "SELECT owner FROM messages WHERE name = ?1 AND expires > ?2",
+                params![message_name, now()],
+                |row| row.get::<_, Option<String>>(0),
+            )
+            .optional()
+            .map_err(|_| Error::ReadError)?
+            .flatten())
+    }
+
+    fn is_private(&self, message_name: &str) -> Result<bool, Error> {
+        Ok(self
+            .connection()?
+            .query_row(
+                "SELECT private FROM messages WHERE name = ?1 AND expires > ?2",
+                params![message_name, now()],
+                |row| row.get::<_, bool>(0),
+            )
+            .optional()
+            .map_err(|_| Error::ReadError)?
+            .unwrap_or(false))
+    }
+
+    fn delete(&self, message_name: &str, owner: &str) -> Result<bool, Error> {
+        let mut connection = self.connection()?;
+        let transaction = connection.transaction()?;
+        let deleted = transaction
+            .execute(
+                "DELETE FROM messages
+                    WHERE name = ?1 AND expires > ?2 AND owner = ?3",
+                params![message_name, now(), owner],
+            )
+            .map_err(|_| Error::WriteError)?;
+        if deleted > 0 {
+            transaction
+                .execute(
+                    "DELETE FROM rooms WHERE message = ?1",
+                    params![message_name],
+                )
+                .map_err(|_| Error::WriteError)?;
+            transaction
+                .execute(
+                    "DELETE FROM recipes WHERE message = ?1",
+                    params![message_name],
+                )
+                .map_err(|_| Error::WriteError)?;
+        }
+
+        transaction.commit().map_err(|_| Error::WriteError)?;
+        Ok(deleted > 0)
+    }
+
+    fn get_summary(
+        &self,
+        message_name: &str,
+    ) -> Result<Option<(messages::Summary, time::SystemTime)>, Error> {
+        let data = self
+            .connection()?
+            .query_row(
+                "SELECT summary, created FROM messages
+                    WHERE name = ?1 AND expires > ?2 AND summary IS NOT NULL",
+                params![message_name, now()],
+                |row| Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, i64>(1)?)),
+            )
+            .optional()
+            .map_err(|_| Error::ReadError)?;
+
+        data.map(|(data, created)| {
+            rmp_serde::from_slice(&data)
+                .map(|summary| (summary, system_time(created)))
+                .map_err(|_| Error::ReadError)
+        })
+        .transpose()
+    }
+
+    fn get_exploration(
+        &self,
+        token: &str,
+    ) -> Result<Option<messages::Exploration>, Error> {
+        let data = self
+            .connection()?
+            .query_row(
+                "SELECT message, current, sequence FROM positions
+                    WHERE token = ?1 AND expires > ?2",
+                params![token, now()],
+                |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, i64>(2)?,
+                    ))
+                },
+            )
+            .optional()
+            .map_err(|_| Error::ReadError)?;
+
+        data.map(|(message, current, sequence)| {
+            Ok(messages::Exploration {
+                message,
+                current: current.parse().map_err(|_| Error::ReadError)?,
+                sequence: sequence as u64,
+            })
+        })
+        .transpose()
+    }
+
+    fn get_visited(&self, token: &str) -> Result<Vec<xid::Identifier>, Error> {
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(
+            "SELECT visits.xid FROM visits
+                JOIN positions ON positions.token = visits.token
+                WHERE visits.token = ?1 AND positions.expires > ?2
+                ORDER BY visits.rowid",
+        )?;
+        let ids = statement
+            .query_map(params![token, now()], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| Error::ReadError)?;
+
+        ids.iter()
+            .map(|id| id.parse().map_err(|_| Error::ReadError))
+            .collect()
+    }
+
+    fn visit(
+        &self,
+        token: &str,
+        message_name: &str,
+        sequence: u64,
+        id: xid::Identifier,
+    ) -> Result<bool, Error> {
+        let now = now();
+        let mut connection = self.connection()?;
+        let transaction = connection.transaction()?;
+
+        let position = transaction
+            .query_row(
+                "SELECT message, sequence FROM positions
+                    WHERE token = ?1 AND expires > ?2",
+                params![token, now],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)),
+            )
+            .optional()
+            .map_err(|_| Error::ReadError)?
+            .filter(|(message, _)| message == message_name);
+        if position.as_ref().map_or(0, |(_, moves)| *moves as u64) != sequence {
+            return Ok(false);
+        }
+
+        // The visits of an expired exploration, or of one of another
+        // message, are discarded
+        if position.is_none() {
+            transaction
+                .execute("DELETE FROM visits WHERE token = ?1", params![token])
+                .map_err(|_| Error::WriteError)?;
+        }
+        transaction
+            .execute(
+                "INSERT OR REPLACE INTO positions
+                    (token, message, current, sequence, expires)
+                    VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    token,
+                    message_name,
+                    id.to_string(),
+                    (sequence + 1) as i64,
+                    now + self.ttl.as_millis() as i64,
+                ],
+            )
+            .map_err(|_| Error::WriteError)?;
+        transaction
+            .execute(
+                "INSERT OR IGNORE INTO visits (token, xid) VALUES (?1, ?2)",
+                params![token, id.to_string()],
+            )
+            .map_err(|_| Error::WriteError)?;
+
+        transaction.commit().map_err(|_| Error::WriteError)?;
+        Ok(true)
+    }
+
+    fn put_nonce(&self, nonce: &str, ttl: time::Duration) -> Result<(), Error> {
+        self.connection()?
+            .execute(
+                "INSERT OR REPLACE INTO nonces (nonce, expires)
+                    VALUES (?1, ?2)",
+                params![nonce, now() + ttl.as_millis() as i64],
+            )
+            .map_err(|_| Error::WriteError)?;
+        Ok(())
+    }
+
+    fn take_nonce(&self, nonce: &str) -> Result<bool, Error> {
+        let deleted = self
+            .connection()?
+            .execute(
+                "DELETE FROM nonces WHERE nonce = ?1 AND expires > ?2",
+                params![nonce, now()],
+            )
+            .map_err(|_| Error::WriteError)?;
+        Ok(deleted > 0)
+    }
+}
+
+/// Upgrades a database created by an earlier version.
+///
+/// Messages created before their creation time was recorded are considered
+/// to have been created at the epoch, and messages created before their TTL
+/// was recorded have the configured TTL.
+///
+/// # Arguments
+/// *  `connection` - The database connection.
+fn migrate(connection: &rusqlite::Connection) -> Result<(), Error> {
+    let columns = connection
+        .prepare("PRAGMA table_info(messages)")?
+        .query_map(params![], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<_>, _>>()?;
+    for (column, definition) in &[
+        ("created", "INTEGER NOT NULL DEFAULT 0"),
+        ("ttl", "INTEGER"),
+        ("summary", "BLOB"),
+        ("owner", "TEXT"),
+        ("private", "INTEGER NOT NULL DEFAULT 0"),
+    ] {
+        if !columns.iter().any(|c| c == column) {
+            connection.execute(
+                &format!(
+                    "ALTER TABLE messages ADD COLUMN {} {}",
+                    column, definition,
+                ),
+                params![],
+            )?;
+        }
+    }
+
+    // Explorations were stored as single records before moves were recorded
+    // individually
+    let legacy = connection
+        .prepare(
+            "SELECT 1 FROM sqlite_master
+                WHERE type = 'table' AND name = 'explorations'",
+        )?
+        .exists(params![])?;
+    if legacy {
+        let explorations = connection
+            .prepare("SELECT token, exploration, expires FROM explorations")?
+            .query_map(params![], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, Vec<u8>>(1)?,
+                    row.get::<_, i64>(2)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        for (token, data, expires) in explorations {
+            let (message, current, visited) = match rmp_serde::from_slice::<(
+                String,
+                xid::Identifier,
+                Vec<xid::Identifier>,
+            )>(&data)
+            {
+                Ok(exploration) => exploration,
+                Err(_) => continue,
+            };
+            connection.execute(
+                "INSERT OR REPLACE INTO positions
+                    (token, message, current, expires)
+                    VALUES (?1, ?2, ?3, ?4)",
+                params![token, message, current.to_string(), expires],
+            )?;
+            for id in visited {
+                connection.execute(
+                    "INSERT OR IGNORE INTO visits (token, xid) VALUES (?1, ?2)",
+                    params![token, id.to_string()],
+                )?;
+            }
+        }
+        connection.execute("DROP TABLE explorations", params![])?;
+    }
+    Ok(())
+}
+
+/// Claims a message name by inserting its record.
+///
+/// This fails with [`Error::Exists`] if an unexpired message with the same
+/// name exists.
+///
+/// # Arguments
+/// *  `connection` - The database connection.
+/// *  `message` - The message.
+/// *  `entrance` - The serialised entrance room.
+/// *  `ttl` - The time-to-live of the message.
+/// *  `options` - The settings of the message.
+fn claim(
+    connection: &rusqlite::Connection,
+    message: &messages::Message,
+    entrance: &[u8],
+    ttl: time::Duration,
+    options: &Options,
+) -> Result<(), Error> {
+    // An expired message with the same name may not yet have been swept
+    sweep(connection, Some(message.name()))?;
+
+    let now = now();
+    let ttl = ttl.as_millis() as i64;
+    let inserted = connection
+        .execute(
+            "INSERT OR IGNORE INTO messages
+                    (name, entrance, expires, created, ttl, summary, owner,
+                        private)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                message.name(),
+                entrance,
+                now + ttl,
+                now,
+                ttl,
+                serialize(&message.summary())?,
+                options.owner,
+                options.private,
+            ],
+        )
+        .map_err(|_| Error::WriteError)?;
+    if inserted == 0 {
+        Err(Error::Exists)
+    } else {
+        Ok(())
+    }
+}
+
+/// Removes the rooms and recipe of an unexpired message, and updates its
+/// entrance and summary.
+///
+/// This fails with [`Error::Missing`] if no such message with the given
+/// owner exists.
+///
+/// # Arguments
+/// *  `connection` - The database connection.
+/// *  `message` - The regenerated message.
+/// *  `owner` - The hash of the token required to manage the message.
+/// *  `entrance` - The serialised entrance room.
+fn clear(
+    connection: &rusqlite::Connection,
+    message: &messages::Message,
+    owner: &str,
+    entrance: &[u8],
+) -> Result<(), Error> {
+    let updated = connection
+        .execute(
+            "UPDATE messages SET entrance = ?2, summary = ?3
+                WHERE name = ?1 AND expires > ?4 AND owner = ?5",
+            params![
+                message.name(),
+                entrance,
+                serialize(&message.summary())?,
+                now(),
+                owner,
+            ],
+        )
+        .map_err(|_| Error::WriteError)?;
+    if updated == 0 {
+        return Err(Error::Missing);
+    }
+
+    connection
+        .execute(
+            "DELETE FROM rooms WHERE message = ?1",
+            params![message.name()],
+        )
+        .map_err(|_| Error::WriteError)?;
+    connection
+        .execute(
+            "DELETE FROM recipes WHERE message = ?1",
+            params![message.name()],
+        )
+        .map_err(|_| Error::WriteError)?;
+    Ok(())
+}
+
+/// Inserts all rooms of a message.
+///
+/// # Arguments
+/// *  `connection` - The database connection.
+/// *  `message` - The message.
+fn insert_rooms(
+    connection: &rusqlite::Connection,
+    message: &messages::Message,
+) -> Result<(), Error> {
+    for room in message.rooms() {
+        connection
+            .execute(
+                "INSERT INTO rooms (message, xid, room) VALUES (?1, ?2, ?3)",
+                params![
+                    message.name(),
+                    room.xid.to_string(),
+                    serialize(&room)?
+                ],
+            )
+            .map_err(|_| Error::WriteError)?;
+    }
+    Ok(())
+}
+
+/// Inserts the recipe of a message.
+///
+/// # Arguments
+/// *  `connection` - The database connection.
+/// *  `message` - The message.
+fn insert_recipe(
+    connection: &rusqlite::Connection,
+    message: &messages::Message,
+) -> Result<(), Error> {
+    connection
+        .execute(
+            "INSERT INTO recipes (message, recipe) VALUES (?1, ?2)",
+            params![message.name(), serialize(message.recipe())?],
+        )
+        .map_err(|_| Error::WriteError)?;
+    Ok(())
+}
+
+/// Removes expired messages.
+///
+/// # Arguments
+/// *  `connection` - The database connection.
+/// *  `message_name` - The name of a single message to consider, or `None` to
+///    consider all messages.
+fn sweep(
+    connection: &rusqlite::Connection,
+    message_name: Option<&str>,
+) -> Result<(), Error> {
+    let now = now();
+    connection.execute(
+        "DELETE FROM rooms WHERE message IN (
+            SELECT name FROM messages
+                WHERE expires <= ?1 AND (?2 IS NULL OR name = ?2)
+        )",
+        params![now, message_name],
+    )?;
+    connection.execute(
+        "DELETE FROM recipes WHERE message IN (
+            SELECT name FROM messages
+                WHERE expires <= ?1 AND (?2 IS NULL OR name = ?2)
+        )",
+        params![now, message_name],
+    )?;
+    connection.execute(
+        "DELETE FROM messages
+            WHERE expires <= ?1 AND (?2 IS NULL OR name = ?2)",
+        params![now, message_name],
+    )?;
+    if message_name.is_none() {
+        connection.execute(
+            "DELETE FROM visits WHERE token IN (
+                SELECT token FROM positions WHERE expires <= ?1
+            )",
+            params![now],
+        )?;
+        connection.execute(
+            "DELETE FROM positions WHERE expires <= ?1",
+            params![now],
+        )?;
+        connection
+            .execute("DELETE FROM nonces WHERE expires <= ?1", params![now])?;
+    }
+    Ok(())
+}
+
+/// Serializes a record for storage.
+///
+/// # Arguments
+/// *  `value` - The record to serialize.
+fn serialize<T>(value: &T) -> Result<Vec<u8>, Error>
+where
+    T: serde::Serialize,
+{
+    rmp_serde::to_vec(value).map_err(|_| Error::WriteError)
+}
+
+#[cfg(test)]
+mod tests {
+    use maze::initialize;
+
+    use super::*;
+
+    /// Opens a store backed by an in-memory database.
+    fn store() -> SqliteStore {
+        SqliteStore::new(
+            ":memory:",
+            time::Duration::from_secs(60),
+            time::Duration::from_secs(60),
+        )
+        .unwrap()
+    }
+
+    /// Generates a small message.
+    ///
+    /// # Arguments
+    /// *  `name` - The name of the message.
+    /// *  `seed` - The seed of the maze.
+    fn message(name: &str, seed: u64) -> messages::Message {
+        messages::Message::new(
+            name,
+            "A",
+            maze::Shape::Hex,
+            initialize::LFSR::new(seed),
+            &xid::Key::new(b"test"),
+        )
+    }
+
+    /// Settings with an owner.
+    fn owned() -> Options {
+        Options {
+            owner: Some("owner".into()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn put_message() {
+        let store = store();
+        store.put_message(&message("test", 1), &owned()).unwrap();
+
+        assert!(store.exists("test").unwrap());
+        assert!(!store.exists("other").unwrap());
+        assert!(store.get_recipe("test").unwrap().is_some());
+        assert!(store.get_summary("test").unwrap().is_some());
+        assert_eq!(store.get_owner("test").unwrap().as_deref(), Some("owner"));
+        assert!(matches!(
+            store.put_message(&message("test", 1), &owned()),
+            Err(Error::Exists)
+        ));
+    }
+
+    #[test]
+    fn get_rooms_keeps_order() {
+        let store = store();
+        store
+            .put_message(&message("test", 1), &Default::default())
+            .unwrap();
+
+        let entrance = store.get("test", None).unwrap().unwrap();
+        let mut ids = entrance.see.clone();
+        ids.reverse();
+        ids.insert(1, xid::Identifier::default());
+        let rooms = store.get_rooms("test", &ids).unwrap();
+        assert_eq!(
+            rooms.iter().map(|room| room.xid).collect::<Vec<_>>(),
+            ids.iter()
+                .copied()
+                .filter(|&id| id != xid::Identifier::default())
+                .collect::<Vec<_>>(),
+        );
+        assert!(store.get_rooms("other", &ids).unwrap().is_empty());
+    }
+
+    #[test]
+    fn put_recipe_stores_no_rooms() {
+        let store = store();
+        store
+            .put_recipe(&message("test", 1), &Default::default())
+            .unwrap();
+
+        assert!(store.exists("test").unwrap());
+        assert!(store.get("test", None).unwrap().is_none());
+        assert!(store.get_recipe("test").unwrap().is_some());
+    }
+
+    #[test]
+    fn replace_checks_owner() {
+        let store = store();
+        store.put_message(&message("test", 1), &owned()).unwrap();
+        let before = store.get("test", None).unwrap().unwrap();
+
+        assert!(matches!(
+            store.replace_message(&message("test", 2), "other"),
+            Err(Error::Missing)
+        ));
+        store.replace_message(&message("test", 2), "owner").unwrap();
+        let after = store.get("test", None).unwrap().unwrap();
+        assert_ne!(before.see, after.see);
+        assert!(store.get("test", Some(before.see[0])).unwrap().is_none());
+    }
+
+    #[test]
+    fn delete_checks_owner() {
+        let store = store();
+        store.put_message(&message("test", 1), &owned()).unwrap();
+
+        assert!(!store.delete("test", "other").unwrap());
+        assert!(store.exists("test").unwrap());
+        assert!(store.delete("test", "owner").unwrap());
+        assert!(!store.exists("test").unwrap());
+        assert!(store.get_recipe("test").unwrap().is_none());
+        assert!(!store.delete("test", "owner").unwrap());
+    }
+
+    #[test]
+    fn nonces_are_single_use() {
+        let store = store();
+        store
+            .put_nonce("nonce", time::Duration::from_secs(60))
+            .unwrap();
+
+        assert!(store.take_nonce("nonce").unwrap());
+        assert!(!store.take_nonce("nonce").unwrap());
+    }
+}
@@ -0,0 +1,460 @@
+//! Exercises the message handlers against an in-memory store.
+
+use std::sync;
+use std::time;
+
+use actix_session::CookieSession;
+use actix_web::cookie::Cookie;
+use actix_web::{http, test, App};
+use maze::initialize;
+use serde_json::{json, Value};
+
+use brizzo::messages;
+use brizzo::messages::generator::Generator;
+use brizzo::messages::jobs::Jobs;
+use brizzo::messages::xid;
+use brizzo::store::{MemoryStore, Store};
+
+/// Creates the application under test.
+///
+/// Identifier cookies expire immediately when `max_age` is zero, so that
+/// every move must rely on the persisted exploration.
+macro_rules! app {
+    ($resume:expr, $max_age:expr) => {{
+        let key = xid::Key::new(b"test");
+        let store: sync::Arc<dyn Store> =
+            sync::Arc::new(MemoryStore::new(time::Duration::from_secs(60)));
+        test::init_service(
+            App::new()
+                .data(store)
+                .data(sync::Arc::new(Generator::new(1, 4, key.clone())))
+                .data(sync::Arc::new(Jobs::default()))
+                .data(messages::Lifetime {
+                    min_ttl: time::Duration::from_secs(1),
+                    max_ttl: time::Duration::from_secs(60),
+                })
+                .data(messages::Navigation {
+                    max_age: $max_age,
+                    resume: $resume,
+                    max_lifetime: None,
+                })
+                .data(key)
+                .app_data(messages::error::json_config())
+                .wrap(CookieSession::signed(&[0; 32]).secure(false))
+                .configure(messages::configure),
+        )
+        .await
+    }};
+    () => {
+        app!(false, xid::IdentifierCookie::DEFAULT_MAX_AGE)
+    };
+}
+
+/// Sends a request and returns the response status, the cookies it set and
+/// its body.
+///
+/// # Arguments
+/// *  `app` - The application.
+/// *  `request` - The request to send.
+/// *  `cookies` - The cookies to send.
+macro_rules! call {
+    ($app:expr, $request:expr, $cookies:expr) => {{
+        let mut request = $request;
+        for cookie in $cookies.iter() {
+            request = request.cookie(cookie.clone());
+        }
+        let response =
+            test::call_service(&mut $app, request.to_request()).await;
+        let status = response.status();
+        let cookies = response
+            .response()
+            .cookies()
+            .map(Cookie::into_owned)
+            .collect::<Vec<_>>();
+        let body = test::read_body(response).await;
+        let body = if body.is_empty() {
+            Value::Null
+        } else {
+            serde_json::from_slice::<Value>(&body).expect("JSON body")
+        };
+        (status, cookies, body)
+    }};
+}
+
+/// A request to create a message.
+///
+/// # Arguments
+/// *  `name` - The name of the message.
+fn create(name: &str) -> test::TestRequest {
+    test::TestRequest::post().uri("/").set_json(&json!({
+        "name": name,
+        "text": "A",
+        "shape": "hex",
+    }))
+}
+
+/// A request to read the current room of a message.
+///
+/// # Arguments
+/// *  `name` - The name of the message.
+fn read(name: &str) -> test::TestRequest {
+    test::TestRequest::get().uri(&format!("/{}", name))
+}
+
+/// A request to move to a room.
+///
+/// # Arguments
+/// *  `name` - The name of the message.
+/// *  `xid` - The room to which to move.
+fn move_to(name: &str, xid: &Value) -> test::TestRequest {
+    test::TestRequest::put()
+        .uri(&format!("/{}", name))
+        .set_json(&json!({ "xid": xid }))
+}
+
+/// Adds a management token to a request.
+///
+/// # Arguments
+/// *  `request` - The request.
+/// *  `token` - The management token, if any.
+fn authorize(
+    request: test::TestRequest,
+    token: Option<&Value>,
+) -> test::TestRequest {
+    match token.and_then(Value::as_str) {
+        Some(token) => request
+            .header(http::header::AUTHORIZATION, format!("Bearer {}", token)),
+        None => request,
+    }
+}
+
+/// A request to remove a message.
+///
+/// # Arguments
+/// *  `name` - The name of the message.
+/// *  `token` - The management token, if any.
+fn remove(name: &str, token: Option<&Value>) -> test::TestRequest {
+    authorize(
+        test::TestRequest::delete().uri(&format!("/{}", name)),
+        token,
+    )
+}
+
+/// A request to regenerate a message.
+///
+/// # Arguments
+/// *  `name` - The name of the message.
+/// *  `token` - The management token, if any.
+/// *  `body` - The changes to the message.
+fn edit(name: &str, token: Option<&Value>, body: Value) -> test::TestRequest {
+    authorize(
+        test::TestRequest::patch()
+            .uri(&format!("/{}", name))
+            .set_json(&body),
+        token,
+    )
+}
+
+#[actix_rt::test]
+async fn create_and_read() {
+    let mut app = app!();
+
+    let (status, _, body) = call!(app, create("Test"), Vec::<Cookie>::new());
+    assert_eq!(status, http::StatusCode::CREATED);
+    assert!(body["token"].is_string());
+
+    let (status, _, body) = call!(app, create("test"), Vec::<Cookie>::new());
+    assert_eq!(status, http::StatusCode::CONFLICT);
+    assert_eq!(body["code"], "already_exists");
+
+    // Names are case insensitive
+    let (status, _, room) = call!(app, read("TEST"), Vec::<Cookie>::new());
+    assert_eq!(status, http::StatusCode::OK);
+    assert!(room["xid"].is_string());
+    assert!(!room["see"].as_array().unwrap().is_empty());
+
+    let (status, _, body) = call!(app, read("other"), Vec::<Cookie>::new());
+    assert_eq!(status, http::StatusCode::NOT_FOUND);
+    assert_eq!(body["code"], "unknown_message");
+}
+
+#[actix_rt::test]
+async fn create_rejects_invalid_names() {
+    let mut app = app!();
+
+    let (status, _, body) =
+        call!(app, create("not valid"), Vec::<Cookie>::new());
+    assert_eq!(status, http::StatusCode::BAD_REQUEST);
+    assert_eq!(body["code"], "name_invalid");
+
+    // The name would collide with the job routes
+    let (status, _, body) = call!(app, create("Jobs"), Vec::<Cookie>::new());
+    assert_eq!(status, http::StatusCode::BAD_REQUEST);
+    assert_eq!(body["code"], "name_invalid");
+}
+
+#[actix_rt::test]
+async fn create_asynchronously() {
+    let mut app = app!();
+
+    let request = create("test").header("prefer", "respond-async");
+    let response = test::call_service(&mut app, request.to_request()).await;
+    assert_eq!(response.status(), http::StatusCode::ACCEPTED);
+    let location = response
+        .headers()
+        .get(http::header::LOCATION)
+        .and_then(|location| location.to_str().ok())
+        .unwrap()
+        .to_string();
+    let job = &location[location.find("/jobs/").unwrap()..];
+
+    let mut status = Value::Null;
+    for _ in 0..100 {
+        let (_, _, body) =
+            call!(app, test::TestRequest::get().uri(job), Vec::<Cookie>::new());
+        status = body;
+        if status["status"] == "done" || status["status"] == "failed" {
+            break;
+        }
+        actix_rt::time::delay_for(time::Duration::from_millis(10)).await;
+    }
+    assert_eq!(status["status"], "done");
+    let (status, _, _) = call!(app, read("test"), Vec::<Cookie>::new());
+    assert_eq!(status, http::StatusCode::OK);
+
+    let (status, _, body) = call!(
+        app,
+        test::TestRequest::get().uri("/jobs/unknown"),
+        Vec::<Cookie>::new()
+    );
+    assert_eq!(status, http::StatusCode::NOT_FOUND);
+    assert_eq!(body["code"], "unknown_job");
+}
+
+#[actix_rt::test]
+async fn move_to_neighbour() {
+    let mut app = app!();
+    call!(app, create("test"), Vec::<Cookie>::new());
+
+    let (_, _, entrance) = call!(app, read("test"), Vec::<Cookie>::new());
+    let (status, cookies, room) = call!(
+        app,
+        move_to("test", &entrance["see"][0]),
+        Vec::<Cookie>::new()
+    );
+    assert_eq!(status, http::StatusCode::OK);
+    assert_eq!(room["xid"], entrance["see"][0]);
+
+    // The position is remembered
+    let (status, _, current) = call!(app, read("test"), cookies);
+    assert_eq!(status, http::StatusCode::OK);
+    assert_eq!(current["xid"], room["xid"]);
+
+    // A room is not its own neighbour
+    let (status, _, body) = call!(app, move_to("test", &room["xid"]), cookies);
+    assert_eq!(status, http::StatusCode::FORBIDDEN);
+    assert_eq!(body["code"], "illegal_transition");
+    assert_eq!(body["details"]["xid"], room["xid"]);
+}
+
+#[actix_rt::test]
+async fn stale_cookie_is_rejected() {
+    let mut app = app!();
+    call!(app, create("test"), Vec::<Cookie>::new());
+
+    let (_, _, entrance) = call!(app, read("test"), Vec::<Cookie>::new());
+    let (_, first, room) = call!(
+        app,
+        move_to("test", &entrance["see"][0]),
+        Vec::<Cookie>::new()
+    );
+    let (status, _, _) = call!(app, move_to("test", &room["see"][0]), first);
+    assert_eq!(status, http::StatusCode::OK);
+
+    // The cookie has already been used to move
+    let (status, _, body) = call!(app, move_to("test", &room["see"][0]), first);
+    assert_eq!(status, http::StatusCode::CONFLICT);
+    assert_eq!(body["code"], "stale_cookie");
+}
+
+#[actix_rt::test]
+async fn stale_exploration_is_rejected() {
+    let mut app = app!(true, time::Duration::from_millis(0));
+    call!(app, create("test"), Vec::<Cookie>::new());
+
+    let (_, _, entrance) = call!(app, read("test"), Vec::<Cookie>::new());
+    let (_, first, room) = call!(
+        app,
+        move_to("test", &entrance["see"][0]),
+        Vec::<Cookie>::new()
+    );
+    let (status, second, _) =
+        call!(app, move_to("test", &room["see"][0]), first);
+    assert_eq!(status, http::StatusCode::OK);
+
+    // The cookie has expired, but the exploration has moved on since
+    let (status, _, body) = call!(app, move_to("test", &room["see"][0]), first);
+    assert_eq!(status, http::StatusCode::CONFLICT);
+    assert_eq!(body["code"], "stale_cookie");
+
+    // The current position can still move
+    let (_, _, current) = call!(app, read("test"), second);
+    let (status, _, _) =
+        call!(app, move_to("test", &current["see"][0]), second);
+    assert_eq!(status, http::StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn delete_requires_token() {
+    let mut app = app!();
+    let (_, _, created) = call!(app, create("test"), Vec::<Cookie>::new());
+
+    let (status, _, body) =
+        call!(app, remove("test", None), Vec::<Cookie>::new());
+    assert_eq!(status, http::StatusCode::UNAUTHORIZED);
+    assert_eq!(body["code"], "unauthorized");
+
+    let (status, _, body) = call!(
+        app,
+        remove("test", Some(&json!("invalid"))),
+        Vec::<Cookie>::new()
+    );
+    assert_eq!(status, http::StatusCode::FORBIDDEN);
+    assert_eq!(body["code"], "forbidden");
+
+    let (status, _, _) = call!(
+        app,
+        remove("test", Some(&created["token"])),
+        Vec::<Cookie>::new()
+    );
+    assert_eq!(status, http::StatusCode::NO_CONTENT);
+
+    let (status, _, body) = call!(app, read("test"), Vec::<Cookie>::new());
+    assert_eq!(status, http::StatusCode::NOT_FOUND);
+    assert_eq!(body["code"], "unknown_message");
+
+    let (status, _, body) = call!(
+        app,
+        remove("test", Some(&created["token"])),
+        Vec::<Cookie>::new()
+    );
+    assert_eq!(status, http::StatusCode::NOT_FOUND);
+    assert_eq!(body["code"], "unknown_message");
+}
+
+#[actix_rt::test]
+async fn edit_resets_positions() {
+    let mut app = app!();
+    let (_, _, created) = call!(app, create("test"), Vec::<Cookie>::new());
+    let (_, _, entrance) = call!(app, read("test"), Vec::<Cookie>::new());
+    let (_, cookies, room) = call!(
+        app,
+        move_to("test", &entrance["see"][0]),
+        Vec::<Cookie>::new()
+    );
+
+    let seed = json!({ "seed": initialize::LFSR::new(42) });
+    let (status, _, body) =
+        call!(app, edit("test", None, seed.clone()), Vec::<Cookie>::new());
+    assert_eq!(status, http::StatusCode::UNAUTHORIZED);
+    assert_eq!(body["code"], "unauthorized");
+    let (status, _, _) = call!(
+        app,
+        edit("test", Some(&created["token"]), seed),
+        Vec::<Cookie>::new()
+    );
+    assert_eq!(status, http::StatusCode::NO_CONTENT);
+
+    // The current room no longer exists
+    let (status, cookies, body) =
+        call!(app, move_to("test", &room["see"][0]), cookies);
+    assert_eq!(status, http::StatusCode::CONFLICT);
+    assert_eq!(body["code"], "position_reset");
+
+    // The position has been dropped, so the explorer starts over
+    let (status, _, current) = call!(app, read("test"), cookies);
+    assert_eq!(status, http::StatusCode::OK);
+    assert_ne!(current["xid"], room["xid"]);
+    let (status, _, _) =
+        call!(app, move_to("test", &current["see"][0]), cookies);
+    assert_eq!(status, http::StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn create_rejects_past_expiry() {
+    let mut app = app!();
+
+    let request = test::TestRequest::post().uri("/").set_json(&json!({
+        "name": "test",
+        "text": "A",
+        "shape": "hex",
+        "expires_at": 1000,
+    }));
+    let (status, _, body) = call!(app, request, Vec::<Cookie>::new());
+    assert_eq!(status, http::StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body["code"], "lifetime_invalid");
+}
+
+#[actix_rt::test]
+async fn meta_describes_message() {
+    let mut app = app!();
+    call!(app, create("test"), Vec::<Cookie>::new());
+
+    let (status, _, meta) = call!(
+        app,
+        test::TestRequest::get().uri("/TEST/meta"),
+        Vec::<Cookie>::new()
+    );
+    assert_eq!(status, http::StatusCode::OK);
+    assert_eq!(meta["shape"], "hex");
+    assert!(meta["rooms"].as_u64().unwrap() > 0);
+    assert!(meta["viewbox"]["width"].as_f64().unwrap() > 0.0);
+    assert!(meta["created"].as_u64().unwrap() > 0);
+    let ttl = meta["ttl"].as_u64().unwrap();
+    assert!(ttl > 0 && ttl <= 60_000);
+
+    // The text is not revealed
+    assert!(meta.get("text").is_none());
+
+    let (status, _, body) = call!(
+        app,
+        test::TestRequest::get().uri("/other/meta"),
+        Vec::<Cookie>::new()
+    );
+    assert_eq!(status, http::StatusCode::NOT_FOUND);
+    assert_eq!(body["code"], "unknown_message");
+}
+
+#[actix_rt::test]
+async fn private_identifiers_depend_on_session() {
+    let mut app = app!();
+    let request = test::TestRequest::post().uri("/").set_json(&json!({
+        "name": "test",
+        "text": "A",
+        "shape": "hex",
+        "private": true,
+    }));
+    let (status, _, _) = call!(app, request, Vec::<Cookie>::new());
+    assert_eq!(status, http::StatusCode::CREATED);
+
+    let (_, first, entrance) = call!(app, read("test"), Vec::<Cookie>::new());
+    let (_, second, other) = call!(app, read("test"), Vec::<Cookie>::new());
+    assert_ne!(entrance["xid"], other["xid"]);
+    assert_ne!(entrance["see"], other["see"]);
+
+    // The same session always sees the same identifiers
+    let (_, _, again) = call!(app, read("test"), first);
+    assert_eq!(again["xid"], entrance["xid"]);
+
+    // Identifiers are only valid in the session that received them
+    let (status, _, body) =
+        call!(app, move_to("test", &entrance["see"][0]), second);
+    assert_eq!(status, http::StatusCode::FORBIDDEN);
+    assert_eq!(body["code"], "illegal_transition");
+    assert_eq!(body["details"]["xid"], other["xid"]);
+
+    let (status, _, room) =
+        call!(app, move_to("test", &entrance["see"][0]), first);
+    assert_eq!(status, http::StatusCode::OK);
+    assert_eq!(room["xid"], entrance["see"][0]);
+}
@@ -12,7 +12,6 @@ function create(name, text, shape) {
             name: name,
             text: text,
             shape: shape,
-            seed: 12345,
         }),
     };
     fetch(BASE_URL, init)
@@ -20,14 +19,21 @@ function create(name, text, shape) {
             if (!r.ok) {
                 throw r;
             } else {
-                return r.blob().then(_ => r);
+                return r.json();
             }
         })
-        .then(() => {
-            window.location.href = "../show/#" + name;
+        .then((body) => {
+            prompt(
+                "Keep this token to remove the message before it expires:",
+                body.token);
+            window.location.href = "../show/#" + name.toLowerCase();
         })
         .catch((r) => {
             switch (r.status) {
+                case 400:
+                case 422:
+                    r.json().then(body => alert(body.message));
+                    return;
                 case 409:
                     alert(""
                         + "A message with that name already exists. Please "
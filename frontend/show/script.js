@@ -1,9 +1,11 @@
 const CANVAS_ID = 'canvas';
+const PROGRESS_ID = 'progress';
 const BASE_URL = (window.location + "")
     .replace(/[^/]*$/, "") + "../api/" + window.location.hash.substring(1);
 const MARGIN = 1.0;
 
 const UNKNOWN = true;
+const UNREACHABLE = false;
 
 
 // The identifiers from the starting room up until the current one
@@ -12,19 +14,26 @@ const trail = [];
 // Our cached rooms
 const rooms = {};
 
+// The number of painted rooms
+let painted = 0;
+
+// Whether the viewbox is known up front
+let sized = false;
+
 
 const main = async () => {
+    await describe();
     let current = await begin();
     while (current) {
         const xid = current.see.find(xid => rooms[xid] === UNKNOWN);
         if (xid !== undefined) {
             trail.push(current);
-            current = await move(xid);
+            current = await move(current, xid);
         } else if (trail.length > 0) {
             while (trail.length > 0) {
                 const back = trail.pop();
                 if (current.see.indexOf(back.xid) >= 0) {
-                    current = await move(back.xid);
+                    current = await move(current, back.xid);
                     break;
                 }
             }
@@ -35,6 +44,35 @@ const main = async () => {
 };
 
 
+/**
+ * Sizes the canvas and progress bar from the message metadata.
+ *
+ * Older messages have no metadata; for those the canvas grows as rooms are
+ * painted.
+ */
+const describe = () => fetch(BASE_URL + "/meta", {credentials: "omit"})
+    .then(r => r.ok ? r.json() : undefined)
+    .then(meta => {
+        if (meta === undefined) {
+            return;
+        }
+        const canvas = document.getElementById(CANVAS_ID);
+        canvas.setAttribute(
+            "viewBox",
+            (meta.viewbox.x - MARGIN) + " "
+                + (meta.viewbox.y - MARGIN) + " "
+                + (meta.viewbox.width + 2 * MARGIN) + " "
+                + (meta.viewbox.height + 2 * MARGIN));
+        sized = true;
+
+        const progress = document.getElementById(PROGRESS_ID);
+        progress.max = meta.rooms;
+        progress.value = 0;
+        progress.hidden = false;
+    })
+    .catch(() => undefined);
+
+
 /**
  * Starts over.
  */
@@ -43,16 +81,37 @@ const begin = () => req("GET", undefined, true).then(cache);
 
 /**
  * Moves to a neighbouring room.
+ *
+ * If the move is rejected, the current room is kept; if the position has
+ * already been used to move, exploration starts over.
  */
-const move = (xid) => req("PUT", {xid: xid}).then(cache).then(paint);
+const move = (current, xid) => req("PUT", {xid: xid})
+    .then(room => room && paint(cache(room)))
+    .catch(e => {
+        switch (e.status) {
+        case 403:
+            // The room is not adjacent to the current room; do not try again
+            if (rooms[xid] === UNKNOWN) {
+                rooms[xid] = UNREACHABLE;
+            }
+            return current;
+        case 409:
+            // Another move was made with the same position; start over
+            trail.length = 0;
+            return begin();
+        default:
+            throw e;
+        }
+    });
 
 
 /**
  * Adds a room to the cache and updates the view.
  */
 const cache = (room) => {
-    if (room && rooms[room.xid] === undefined
-            || rooms[room.xid] === UNKNOWN) {
+    if (room && (rooms[room.xid] === undefined
+            || rooms[room.xid] === UNKNOWN
+            || rooms[room.xid] === UNREACHABLE)) {
         trail.push(room.xid)
         rooms[room.xid] = room;
         room.see.forEach(remember);
@@ -73,16 +132,22 @@ const remember = (xid) => {
 
 /**
  * Performs a request.
+ *
+ * Rejected moves are passed on to the caller; other failures are reported
+ * and yield no room.
  */
 const req = (method, data, reset) => fetch(BASE_URL, init(method, data, reset))
     .then(r => {
         if (r.ok) {
-            return r.text();
+            return r.text().then(JSON.parse);
         } else {
             throw r;
         }
     }).catch(e => {
         switch (e.status) {
+        case 403:
+        case 409:
+            throw e;
         case 404:
             alert("Not found!");
                 break;
@@ -95,7 +160,8 @@ const req = (method, data, reset) => fetch(BASE_URL, init(method, data, reset))
                 + e.status);
             break;
         }
-    }).then(JSON.parse);
+        return undefined;
+    });
 
 
 /**
@@ -129,30 +195,36 @@ const paint = (room) => {
     el.setAttribute("points", room.pos.map(p => `${p.x},${p.y}`).join(" "));
     el.style.fill = room.col;
 
-    // Update the viewbox
-    let x = canvas.viewBox.baseVal.x;
-    let y = canvas.viewBox.baseVal.y;
-    let width = canvas.viewBox.baseVal.width;
-    let height = canvas.viewBox.baseVal.height;
-    room.pos.forEach(pos => {
-        x = Math.min(
-            x,
-            pos.x - MARGIN);
-        y = Math.min(
-            y,
-            pos.y - MARGIN);
-        width = Math.max(
-            width,
-            pos.x - x + MARGIN);
-        height = Math.max(
-            height,
-            pos.y - y + MARGIN);
-    });
-    canvas.setAttribute(
-        "viewBox",
-        x + " " + y + " " + width + " " + height);
+    // Update the viewbox unless it is known up front
+    if (!sized) {
+        let x = canvas.viewBox.baseVal.x;
+        let y = canvas.viewBox.baseVal.y;
+        let width = canvas.viewBox.baseVal.width;
+        let height = canvas.viewBox.baseVal.height;
+        room.pos.forEach(pos => {
+            x = Math.min(
+                x,
+                pos.x - MARGIN);
+            y = Math.min(
+                y,
+                pos.y - MARGIN);
+            width = Math.max(
+                width,
+                pos.x - x + MARGIN);
+            height = Math.max(
+                height,
+                pos.y - y + MARGIN);
+        });
+        canvas.setAttribute(
+            "viewBox",
+            x + " " + y + " " + width + " " + height);
+    }
 
     canvas.appendChild(el);
 
+    // Update the progress
+    painted++;
+    document.getElementById(PROGRESS_ID).value = painted;
+
     return room;
 };